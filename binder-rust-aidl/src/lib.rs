@@ -0,0 +1,694 @@
+//! Generates `binder-rust` proxies from AIDL files, so they do not have to be written by hand.
+//!
+//! Call `compile` from a build script and include the generated file:
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     binder_rust_aidl::compile("aidl/com/example/IMyService.aidl").unwrap();
+//! }
+//!
+//! // main.rs
+//! mod aidl {
+//!     include!(concat!(env!("OUT_DIR"), "/IMyService.rs"));
+//! }
+//! ```
+//!
+//! Each interface becomes a struct wrapping a `Service`, with a method per AIDL method, and each
+//! structured parcelable becomes a struct deriving `Parcelable`, so the crate using the generated
+//! code must depend on both `binder-rust` and `parcelable_derive`.
+//!
+//! Only a subset of AIDL is understood: `in` parameters of type `boolean`, `byte`, `char`, `int`,
+//! `long`, `String` or a parcelable, `oneway` methods and interfaces, and explicit transaction
+//! codes. Anything else, such as arrays, `out` parameters or constants, is rejected with an error
+//! naming the line.
+
+use std::{
+    env,
+    ffi::OsStr,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("stdio error")]
+    StdioError(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+    #[error("OUT_DIR is not set, compile must be called from a build script")]
+    NoOutDir,
+}
+
+/// Generate the Rust code for `aidl` into `$OUT_DIR`, in a file named after it with an `.rs`
+/// extension, and tell cargo to rerun the build script when it changes. Returns the path of the
+/// generated file.
+pub fn compile<P: AsRef<Path>>(aidl: P) -> Result<PathBuf, Error> {
+    let aidl = aidl.as_ref();
+    println!("cargo:rerun-if-changed={}", aidl.display());
+
+    let out_dir = env::var_os("OUT_DIR").ok_or(Error::NoOutDir)?;
+    let name = aidl.file_stem().unwrap_or_else(|| OsStr::new("aidl"));
+    let output = Path::new(&out_dir).join(name).with_extension("rs");
+    fs::write(&output, generate(&fs::read_to_string(aidl)?)?)?;
+    Ok(output)
+}
+
+/// Generate the Rust code for the AIDL in `source`
+pub fn generate(source: &str) -> Result<String, Error> {
+    let document = Parser::new(source)?.document()?;
+
+    let mut code = String::new();
+    code.push_str("// Generated by binder-rust-aidl, do not edit.\n\n");
+    code.push_str("#[allow(unused_imports)]\n");
+    code.push_str("use binder_rust::{Error, Parcel, Parcelable, ParcelableFields, Service, ServiceManager, String16};\n");
+    code.push_str("#[allow(unused_imports)]\n");
+    code.push_str("use parcelable_derive::Parcelable;\n");
+
+    for item in &document.items {
+        code.push('\n');
+        let descriptor = match &document.package {
+            Some(package) => format!("{}.{}", package, item.name()),
+            None => item.name().to_string(),
+        };
+        match item {
+            Item::Interface(interface) => generate_interface(&mut code, &descriptor, interface),
+            Item::Parcelable(parcelable) => generate_parcelable(&mut code, &descriptor, parcelable),
+        }
+    }
+    Ok(code)
+}
+
+fn generate_parcelable(code: &mut String, descriptor: &str, parcelable: &Parcelable) {
+    let _ = writeln!(code, "/// The `{}` parcelable", descriptor);
+    code.push_str("#[derive(Debug, Default, Parcelable)]\n");
+    code.push_str("#[parcelable(stable)]\n");
+    let _ = writeln!(code, "pub struct {} {{", parcelable.name);
+    for field in &parcelable.fields {
+        let ty = match &field.ty {
+            // Boxed, or the struct would contain itself
+            Type::Parcelable(name) if *name == parcelable.name => format!("Option<Box<{}>>", name),
+            ty => ty.field_type(),
+        };
+        let _ = writeln!(code, "    pub {}: {},", rust_name(&field.name), ty);
+    }
+    code.push_str("}\n");
+}
+
+fn generate_interface(code: &mut String, descriptor: &str, interface: &Interface) {
+    let name = &interface.name;
+    let _ = writeln!(code, "/// A proxy for the `{}` interface", descriptor);
+    let _ = writeln!(code, "pub struct {}<'a> {{", name);
+    code.push_str("    service: Service<'a>,\n");
+    code.push_str("}\n\n");
+
+    let _ = writeln!(code, "impl<'a> {}<'a> {{", name);
+    let _ = writeln!(code, "    pub const DESCRIPTOR: &'static str = {:?};", descriptor);
+    for (method, transaction_code) in interface.methods.iter().zip(interface.codes()) {
+        let _ = writeln!(code, "    pub const {}: u32 = {};", method.code_name(), transaction_code);
+    }
+    code.push_str(concat!(
+        "\n",
+        "    /// Get the proxy for the service registered as `name`\n",
+        "    pub fn get(service_manager: &mut ServiceManager, name: &'a str) -> Result<Self, Error> {\n",
+        "        Ok(Self::from_service(service_manager.get_service(name, Self::DESCRIPTOR)?))\n",
+        "    }\n",
+        "\n",
+        "    /// Wrap a service implementing the interface, such as one read from a parcel\n",
+        "    pub fn from_service(service: Service<'a>) -> Self {\n",
+        "        Self { service: service.cast(Self::DESCRIPTOR) }\n",
+        "    }\n",
+        "\n",
+        "    /// The underlying service, for calls the proxy does not cover\n",
+        "    pub fn service(&mut self) -> &mut Service<'a> {\n",
+        "        &mut self.service\n",
+        "    }\n",
+    ));
+
+    for method in &interface.methods {
+        code.push('\n');
+        let _ = writeln!(code, "    /// `{}`", method.signature);
+        let arguments: Vec<String> = method
+            .arguments
+            .iter()
+            .map(|argument| format!(", {}: {}", rust_name(&argument.name), argument.ty.argument_type()))
+            .collect();
+        let _ = writeln!(
+            code,
+            "    pub fn {}(&mut self{}) -> Result<{}, Error> {{",
+            rust_name(&method.name),
+            arguments.concat(),
+            method.return_type.return_type()
+        );
+        code.push_str("        let mut aidl_data = Parcel::empty();\n");
+        for argument in &method.arguments {
+            let _ = writeln!(code, "        {}", argument.ty.write(&rust_name(&argument.name)));
+        }
+        let call = format!("(Self::{}, &mut aidl_data)", method.code_name());
+        if method.oneway {
+            let _ = writeln!(code, "        self.service.call_oneway{}", call);
+        } else if method.return_type == Type::Void {
+            let _ = writeln!(code, "        self.service.call{}?;", call);
+            code.push_str("        Ok(())\n");
+        } else {
+            let _ = writeln!(code, "        let mut aidl_reply = self.service.call{}?;", call);
+            let _ = writeln!(code, "        {}", method.return_type.read());
+        }
+        code.push_str("    }\n");
+    }
+    code.push_str("}\n");
+}
+
+/// The types which can be used for fields, arguments and return values
+#[derive(Clone, Debug, PartialEq)]
+enum Type {
+    Void,
+    Boolean,
+    Byte,
+    Char,
+    Int,
+    Long,
+    String,
+    Parcelable(String),
+}
+
+impl Type {
+    /// The type of a parcelable field
+    fn field_type(&self) -> String {
+        match self {
+            Type::String => "String16".to_string(),
+            // Parcelable fields are written with `writeTypedObject`, so may be null
+            Type::Parcelable(name) => format!("Option<{}>", name),
+            _ => self.return_type(),
+        }
+    }
+
+    /// The type of a method argument
+    fn argument_type(&self) -> String {
+        match self {
+            Type::String => "&str".to_string(),
+            Type::Parcelable(name) => format!("&{}", name),
+            _ => self.return_type(),
+        }
+    }
+
+    /// The type of a method's result
+    fn return_type(&self) -> String {
+        match self {
+            Type::Void => "()",
+            Type::Boolean => "bool",
+            Type::Byte => "i8",
+            Type::Char => "char",
+            Type::Int => "i32",
+            Type::Long => "i64",
+            Type::String => "String",
+            Type::Parcelable(name) => name,
+        }
+        .to_string()
+    }
+
+    /// The statement writing the argument `name` to `aidl_data`
+    fn write(&self, name: &str) -> String {
+        match self {
+            // Bytes are written as a whole i32, like Android's `writeByte` does
+            Type::Byte => format!("aidl_data.write_i32({} as i32)?;", name),
+            Type::String => format!("aidl_data.write_str16({})?;", name),
+            Type::Parcelable(_) => format!("aidl_data.write_typed_object(Some({}))?;", name),
+            _ => format!("{}.serialize(&mut aidl_data)?;", name),
+        }
+    }
+
+    /// The expression reading the result from `aidl_reply`
+    fn read(&self) -> String {
+        match self {
+            Type::Byte => "Ok(aidl_reply.read_i32()? as i8)".to_string(),
+            Type::String => "aidl_reply.read_str16()".to_string(),
+            Type::Parcelable(_) => "aidl_reply.read_typed_object()?.ok_or(Error::DeserializationError)".to_string(),
+            _ => format!("{}::deserialize(&mut aidl_reply)", self.return_type()),
+        }
+    }
+}
+
+struct Document {
+    package: Option<String>,
+    items: Vec<Item>,
+}
+
+enum Item {
+    Interface(Interface),
+    Parcelable(Parcelable),
+}
+
+impl Item {
+    fn name(&self) -> &str {
+        match self {
+            Item::Interface(interface) => &interface.name,
+            Item::Parcelable(parcelable) => &parcelable.name,
+        }
+    }
+}
+
+struct Interface {
+    name: String,
+    methods: Vec<Method>,
+}
+
+impl Interface {
+    /// The transaction codes of the methods, `FIRST_CALL_TRANSACTION` (1) plus either their index
+    /// or, like the AIDL compiler, the id given explicitly with `= N`
+    fn codes(&self) -> Vec<u32> {
+        self.methods
+            .iter()
+            .enumerate()
+            .map(|(index, method)| method.code.map_or(index as u32 + 1, |id| id + 1))
+            .collect()
+    }
+}
+
+struct Method {
+    name: String,
+    oneway: bool,
+    return_type: Type,
+    arguments: Vec<Argument>,
+    code: Option<u32>,
+    /// The declaration as written in the AIDL file, for the doc comment
+    signature: String,
+}
+
+impl Method {
+    /// The name of the constant holding the transaction code
+    fn code_name(&self) -> String {
+        format!("TRANSACTION_{}", snake_case(&self.name).to_uppercase())
+    }
+}
+
+struct Argument {
+    name: String,
+    ty: Type,
+}
+
+struct Parcelable {
+    name: String,
+    fields: Vec<Field>,
+}
+
+struct Field {
+    name: String,
+    ty: Type,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u32),
+    Punct(char),
+}
+
+struct Parser {
+    /// The tokens with the line they start on
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, Error> {
+        let mut tokens = vec![];
+        let mut line = 1;
+        let mut chars = source.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\n' => line += 1,
+                c if c.is_whitespace() => {}
+                '/' if chars.peek() == Some(&'/') => {
+                    while matches!(chars.peek(), Some(&c) if c != '\n') {
+                        chars.next();
+                    }
+                }
+                '/' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    let mut previous = ' ';
+                    loop {
+                        match chars.next() {
+                            Some('/') if previous == '*' => break,
+                            Some(c) => {
+                                if c == '\n' {
+                                    line += 1;
+                                }
+                                previous = c;
+                            }
+                            None => return Err(Error::Syntax { line, message: "unterminated comment".to_string() }),
+                        }
+                    }
+                }
+                c if c.is_ascii_digit() => {
+                    let mut number = c.to_string();
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                        number.push(c);
+                        chars.next();
+                    }
+                    let number = number
+                        .parse()
+                        .map_err(|_| Error::Syntax { line, message: format!("invalid number {}", number) })?;
+                    tokens.push((Token::Number(number), line));
+                }
+                c if c.is_alphabetic() || c == '_' => {
+                    let mut ident = c.to_string();
+                    while let Some(&c) = chars.peek().filter(|&&c| c.is_alphanumeric() || c == '_' || c == '.') {
+                        ident.push(c);
+                        chars.next();
+                    }
+                    tokens.push((Token::Ident(ident), line));
+                }
+                c => tokens.push((Token::Punct(c), line)),
+            }
+        }
+        Ok(Self { tokens, position: 0 })
+    }
+
+    fn document(&mut self) -> Result<Document, Error> {
+        let mut package = None;
+        let mut items = vec![];
+        while self.peek().is_some() {
+            self.skip_annotations()?;
+            let oneway = self.eat_ident("oneway");
+            match self.ident()?.as_str() {
+                "package" if !oneway => {
+                    package = Some(self.ident()?);
+                    self.punct(';')?;
+                }
+                // Imported parcelables are referred to by their simple name, which must be in
+                // scope where the generated code is included
+                "import" if !oneway => {
+                    self.ident()?;
+                    self.punct(';')?;
+                }
+                "interface" => items.push(Item::Interface(self.interface(oneway)?)),
+                "parcelable" if !oneway => {
+                    if let Some(parcelable) = self.parcelable()? {
+                        items.push(Item::Parcelable(parcelable));
+                    }
+                }
+                other => return Err(self.error(format!("unexpected {:?}", other))),
+            }
+        }
+        Ok(Document { package, items })
+    }
+
+    fn interface(&mut self, oneway: bool) -> Result<Interface, Error> {
+        let name = self.ident()?;
+        self.punct('{')?;
+        let mut methods = vec![];
+        while !self.eat_punct('}') {
+            methods.push(self.method(oneway)?);
+        }
+        Ok(Interface { name, methods })
+    }
+
+    fn method(&mut self, interface_oneway: bool) -> Result<Method, Error> {
+        let start = self.position;
+        self.skip_annotations()?;
+        if self.eat_ident("const") {
+            return Err(self.error("constants are not supported".to_string()));
+        }
+        let oneway = self.eat_ident("oneway") || interface_oneway;
+        let return_type = self.ty()?;
+        if oneway && return_type != Type::Void {
+            return Err(self.error("oneway methods must return void".to_string()));
+        }
+        let name = self.ident()?;
+        self.punct('(')?;
+        let mut arguments = vec![];
+        while !self.eat_punct(')') {
+            if !arguments.is_empty() {
+                self.punct(',')?;
+            }
+            self.skip_annotations()?;
+            if self.eat_ident("out") || self.eat_ident("inout") {
+                return Err(self.error("only in parameters are supported".to_string()));
+            }
+            self.eat_ident("in");
+            let ty = self.ty()?;
+            if ty == Type::Void {
+                return Err(self.error("parameters can not be void".to_string()));
+            }
+            arguments.push(Argument { name: self.ident()?, ty });
+        }
+        let code = if self.eat_punct('=') {
+            match self.next()? {
+                Token::Number(code) => Some(code),
+                _ => return Err(self.error("expected a transaction code".to_string())),
+            }
+        } else {
+            None
+        };
+        let signature = self.text(start, self.position);
+        self.punct(';')?;
+        Ok(Method { name, oneway, return_type, arguments, code, signature })
+    }
+
+    /// A structured parcelable, or `None` for a declaration of one defined elsewhere
+    fn parcelable(&mut self) -> Result<Option<Parcelable>, Error> {
+        let name = self.ident()?;
+        if self.eat_punct(';') {
+            return Ok(None);
+        }
+        self.punct('{')?;
+        let mut fields = vec![];
+        while !self.eat_punct('}') {
+            self.skip_annotations()?;
+            let ty = self.ty()?;
+            match ty {
+                Type::Void => return Err(self.error("fields can not be void".to_string())),
+                // A derived i8 field would be written as a single byte, rather than an i32
+                Type::Byte => return Err(self.error("byte fields are not supported".to_string())),
+                _ => {}
+            }
+            let name = self.ident()?;
+            if self.peek() == Some(&Token::Punct('=')) {
+                return Err(self.error("default values are not supported".to_string()));
+            }
+            self.punct(';')?;
+            fields.push(Field { name, ty });
+        }
+        Ok(Some(Parcelable { name, fields }))
+    }
+
+    fn ty(&mut self) -> Result<Type, Error> {
+        let name = self.ident()?;
+        if self.peek() == Some(&Token::Punct('[')) || self.peek() == Some(&Token::Punct('<')) {
+            return Err(self.error("arrays and generic types are not supported".to_string()));
+        }
+        let name = name.rsplit('.').next().unwrap_or_default().to_string();
+        Ok(match name.as_str() {
+            "void" => Type::Void,
+            "boolean" => Type::Boolean,
+            "byte" => Type::Byte,
+            "char" => Type::Char,
+            "int" => Type::Int,
+            "long" => Type::Long,
+            "String" => Type::String,
+            "float" | "double" | "CharSequence" | "IBinder" | "FileDescriptor" | "ParcelFileDescriptor" | "List" | "Map" => {
+                return Err(self.error(format!("{} is not supported", name)))
+            }
+            _ => Type::Parcelable(name),
+        })
+    }
+
+    /// Skip annotations, such as `@nullable` or `@JavaDerive(toString=true)`
+    fn skip_annotations(&mut self) -> Result<(), Error> {
+        while self.eat_punct('@') {
+            self.ident()?;
+            if self.eat_punct('(') {
+                let mut depth = 1;
+                while depth > 0 {
+                    match self.next()? {
+                        Token::Punct('(') => depth += 1,
+                        Token::Punct(')') => depth -= 1,
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The tokens from `start` to `end`, joined back into text
+    fn text(&self, start: usize, end: usize) -> String {
+        let mut text = String::new();
+        let mut previous = None;
+        for (token, _) in &self.tokens[start..end] {
+            let joined = matches!(token, Token::Punct('(' | ')' | ','))
+                || matches!(previous, None | Some(&Token::Punct('(' | '@')));
+            if !joined {
+                text.push(' ');
+            }
+            match token {
+                Token::Ident(ident) => text.push_str(ident),
+                Token::Number(number) => text.push_str(&number.to_string()),
+                Token::Punct(c) => text.push(*c),
+            }
+            previous = Some(token);
+        }
+        text
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Result<Token, Error> {
+        let token = self.peek().cloned().ok_or_else(|| self.error("unexpected end of file".to_string()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Ident(ident) => Ok(ident),
+            other => {
+                self.position -= 1;
+                Err(self.error(format!("expected a name, found {:?}", other)))
+            }
+        }
+    }
+
+    fn punct(&mut self, expected: char) -> Result<(), Error> {
+        if self.eat_punct(expected) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected {:?}", expected)))
+        }
+    }
+
+    fn eat_punct(&mut self, expected: char) -> bool {
+        self.eat(&Token::Punct(expected))
+    }
+
+    fn eat_ident(&mut self, expected: &str) -> bool {
+        self.eat(&Token::Ident(expected.to_string()))
+    }
+
+    fn eat(&mut self, expected: &Token) -> bool {
+        if self.peek() == Some(expected) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// A syntax error at the current token
+    fn error(&self, message: String) -> Error {
+        let line = self
+            .tokens
+            .get(self.position)
+            .or_else(|| self.tokens.last())
+            .map_or(1, |(_, line)| *line);
+        Error::Syntax { line, message }
+    }
+}
+
+/// Convert an AIDL name, such as `clearAllURLs`, to snake case, such as `clear_all_urls`
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut previous = None;
+    for c in name.chars() {
+        if c.is_uppercase() && matches!(previous, Some(p) if char::is_lowercase(p) || char::is_ascii_digit(&p)) {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+        previous = Some(c);
+    }
+    snake
+}
+
+/// The snake case Rust name for an AIDL method, parameter or field, escaped if it is a keyword
+fn rust_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false", "fn", "for",
+        "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "static", "struct",
+        "trait", "true", "type", "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final",
+        "macro", "override", "priv", "try", "typeof", "unsized", "virtual", "yield",
+    ];
+    let name = snake_case(name);
+    match name.as_str() {
+        // These can not be raw identifiers
+        "self" | "super" | "crate" => format!("{}_", name),
+        _ if KEYWORDS.contains(&name.as_str()) => format!("r#{}", name),
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The line of the syntax error `source` is rejected with
+    fn error_line(source: &str) -> usize {
+        match generate(source) {
+            Err(Error::Syntax { line, .. }) => line,
+            Err(error) => panic!("unexpected error: {}", error),
+            Ok(_) => panic!("{:?} was accepted", source),
+        }
+    }
+
+    #[test]
+    fn methods_are_numbered_from_the_first_call() {
+        let code = generate("package com.example; interface ITest { void first(); int second(int value); }").unwrap();
+        assert!(code.contains("pub const DESCRIPTOR: &'static str = \"com.example.ITest\";"));
+        assert!(code.contains("pub const TRANSACTION_FIRST: u32 = 1;"));
+        assert!(code.contains("pub const TRANSACTION_SECOND: u32 = 2;"));
+        assert!(code.contains("pub fn second(&mut self, value: i32) -> Result<i32, Error> {"));
+        assert!(code.contains("value.serialize(&mut aidl_data)?;"));
+        assert!(code.contains("i32::deserialize(&mut aidl_reply)"));
+    }
+
+    #[test]
+    fn explicit_ids_are_offset_from_the_first_call() {
+        let code = generate("interface ITest { void first() = 0; void second() = 10; }").unwrap();
+        assert!(code.contains("pub const TRANSACTION_FIRST: u32 = 1;"));
+        assert!(code.contains("pub const TRANSACTION_SECOND: u32 = 11;"));
+    }
+
+    #[test]
+    fn oneway_methods_do_not_wait_for_a_reply() {
+        let code = generate("interface ITest { oneway void notify(int value); void wait(); }").unwrap();
+        assert!(code.contains("self.service.call_oneway(Self::TRANSACTION_NOTIFY, &mut aidl_data)"));
+        assert!(code.contains("self.service.call(Self::TRANSACTION_WAIT, &mut aidl_data)?;"));
+
+        let code = generate("oneway interface ITest { void notify(); }").unwrap();
+        assert!(code.contains("self.service.call_oneway(Self::TRANSACTION_NOTIFY, &mut aidl_data)"));
+    }
+
+    #[test]
+    fn parcelables_are_passed_as_typed_objects() {
+        let code = generate(
+            "parcelable Point { int x; String label; Point next; }\n\
+             interface ITest { Point move(in Point point); }",
+        )
+        .unwrap();
+        assert!(code.contains("pub struct Point {"));
+        assert!(code.contains("pub x: i32,"));
+        assert!(code.contains("pub label: String16,"));
+        assert!(code.contains("pub next: Option<Box<Point>>,"));
+        assert!(code.contains("pub fn r#move(&mut self, point: &Point) -> Result<Point, Error> {"));
+        assert!(code.contains("aidl_data.write_typed_object(Some(point))?;"));
+        assert!(code.contains("aidl_reply.read_typed_object()?.ok_or(Error::DeserializationError)"));
+    }
+
+    #[test]
+    fn unsupported_constructs_are_rejected() {
+        assert_eq!(error_line("interface ITest {\n    const int VALUE = 1;\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    oneway int get();\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    void get(out int value);\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    void get(in int[] values);\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    void get(float value);\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    void get() = x;\n}"), 2);
+        assert_eq!(error_line("parcelable Point {\n    byte x;\n}"), 2);
+        assert_eq!(error_line("parcelable Point {\n    int x = 1;\n}"), 2);
+        assert_eq!(error_line("interface ITest {\n    /* unterminated"), 2);
+    }
+}
@@ -0,0 +1,3 @@
+fn main() {
+    binder_rust_aidl::compile("aidl/com/example/IMyService.aidl").unwrap();
+}
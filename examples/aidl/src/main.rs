@@ -0,0 +1,20 @@
+/// Calls the service implemented by the `myservice` example through a proxy generated from
+/// `aidl/com/example/IMyService.aidl` by the build script.
+
+use binder_rust::{Error, ServiceManager};
+
+#[allow(dead_code)]
+mod aidl {
+    include!(concat!(env!("OUT_DIR"), "/IMyService.rs"));
+}
+
+use aidl::IMyService;
+
+fn main() -> Result<(), Error> {
+    let mut service_manager = ServiceManager::new()?;
+
+    let mut my_service = IMyService::get(&mut service_manager, "myservice")?;
+    println!("response: {:?}", my_service.echo("Hello World")?);
+
+    Ok(())
+}
@@ -0,0 +1,22 @@
+/// Lists the registered services, by calling `IServiceManager.listServices` on the context object.
+/// Requires root to run.
+
+use binder_rust::{DumpPriority, Error, Parcel, ServiceManager};
+
+const LIST_SERVICES: u32 = 4;
+
+fn main() -> Result<(), Error> {
+    let service_manager = ServiceManager::new()?;
+    let mut context_object = service_manager.context_object();
+
+    let mut parcel = Parcel::empty();
+    parcel.write_u32(DumpPriority::ALL.bits())?;
+    let mut res = context_object.call(LIST_SERVICES, &mut parcel)?;
+
+    let count = res.read_i32()?;
+    for _ in 0..count {
+        println!("{}", res.read_str16()?);
+    }
+
+    Ok(())
+}
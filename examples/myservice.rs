@@ -1,6 +1,6 @@
 /// Implements a simple service which echos any string it receives. Requires root to run.
 
-use binder_rust::{Binder, BinderService, Parcel, ServiceManager};
+use binder_rust::{BinderService, DumpPriority, Error, Parcel, ServiceManager};
 
 #[macro_use]
 extern crate num_derive;
@@ -24,31 +24,36 @@ impl BinderService for MyService {
         println!("Got command: {} -> {:?}", code, MyServiceCommands::from_u32(code));
         match MyServiceCommands::from_u32(code).unwrap() {
             MyServiceCommands::GetFile => {
-                let filename = &std::ffi::CString::new(data.read_str16()).unwrap();
+                let filename = &std::ffi::CString::new(data.read_str16().unwrap()).unwrap();
                 let fd = unsafe { open(filename.as_ptr(), O_RDWR | O_CREAT) };
                 println!("filename: {:?}, fd: {}", filename, fd);
                 let mut parcel = Parcel::empty();
-                parcel.write_u32(0);
-                parcel.write_file_descriptor(fd, false);
+                parcel.write_u32(0).unwrap();
+                parcel.write_file_descriptor(fd, false).unwrap();
                 parcel
             },
             MyServiceCommands::Echo => {
                 let mut parcel = Parcel::empty();
-                parcel.write_u32(0); //status
-                parcel.write_str16(&data.read_str16());
+                parcel.write_u32(0).unwrap(); //status
+                parcel.write_str16(&data.read_str16().unwrap()).unwrap();
                 parcel
             }
         }
     }
+
+    /// Shown by `dumpsys myservice`
+    fn dump(&self, args: &[String]) -> String {
+        format!("MyService\n  commands: Echo, GetFile\n  dump args: {:?}\n", args)
+    }
 }
-fn main() {
-    let mut service_manager = &mut ServiceManager::new();
+fn main() -> Result<(), Error> {
+    let mut service_manager = ServiceManager::new()?;
 
     let myservice = MyService {};
 
-    let mut service = service_manager.register_service(&myservice, "myservice", "com.example.IMyService", true, 0);
+    let mut service = service_manager.register_service(&myservice, "myservice", "com.example.IMyService", true, DumpPriority::DEFAULT)?;
 
 
-    service.run();
+    service.run()
 }
 
@@ -0,0 +1,27 @@
+/// A typed proxy for the service implemented by the `myservice` example, built on `Proxy`.
+
+use binder_rust::{Error, Proxy, Service, ServiceManager, String16};
+
+struct MyServiceProxy<'a> {
+    service: Service<'a>,
+}
+
+impl<'a> MyServiceProxy<'a> {
+    const ECHO: u32 = 1;
+
+    fn echo(&mut self, message: &str) -> Result<String16, Error> {
+        self.service.call_method(Self::ECHO, &message.parse::<String16>()?)
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let mut service_manager = ServiceManager::new()?;
+
+    let mut proxy = MyServiceProxy {
+        service: service_manager.get_service("myservice", "com.example.IMyService")?,
+    };
+
+    println!("response: {:?}", proxy.echo("Hello World")?);
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+extern crate binder_rust;
+use binder_rust::{Error, Parcel, Parcelable, ParcelableFields, TaggedParcelable};
+use parcelable_derive::Parcelable;
+
+/// A shape, sent as a tag followed by the fields of the concrete shape
+trait Shape: TaggedParcelable {
+    fn area(&self) -> f64;
+}
+
+#[derive(Debug, Parcelable)]
+struct Square {
+    side: i32,
+}
+
+impl TaggedParcelable for Square {
+    fn tag(&self) -> i32 {
+        1
+    }
+}
+
+impl Shape for Square {
+    fn area(&self) -> f64 {
+        (self.side * self.side) as f64
+    }
+}
+
+#[derive(Debug, Parcelable)]
+struct Rectangle {
+    width: i32,
+    height: i32,
+}
+
+impl TaggedParcelable for Rectangle {
+    fn tag(&self) -> i32 {
+        2
+    }
+}
+
+impl Shape for Rectangle {
+    fn area(&self) -> f64 {
+        (self.width * self.height) as f64
+    }
+}
+
+fn create_shape(tag: i32, parcel: &mut Parcel) -> Result<Box<dyn Shape>, Error> {
+    Ok(match tag {
+        1 => Box::new(Square::deserialize(parcel)?),
+        2 => Box::new(Rectangle::deserialize(parcel)?),
+        _ => return Err(Error::BadEnumValue),
+    })
+}
+
+#[derive(Debug, Parcelable)]
+struct Drawing {
+    name: String,
+    #[parcelable(tagged_trait = "create_shape")]
+    first: Box<dyn Shape>,
+    #[parcelable(tagged_trait = "create_shape")]
+    second: Box<dyn Shape>,
+}
+
+fn main() -> Result<(), Error> {
+    let drawing = Drawing {
+        name: "shapes".to_string(),
+        first: Box::new(Square { side: 3 }),
+        second: Box::new(Rectangle { width: 2, height: 5 }),
+    };
+
+    let mut parcel = Parcel::empty();
+    drawing.serialize(&mut parcel)?;
+    println!("serialized: {}", parcel.to_hex());
+
+    parcel.set_position(0);
+    let drawing = Drawing::deserialize(&mut parcel)?;
+    println!("deserialized: {:?}", drawing);
+    assert_eq!(drawing.first.area(), 9.0);
+    assert_eq!(drawing.second.area(), 10.0);
+
+    Ok(())
+}
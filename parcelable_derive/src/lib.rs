@@ -1,7 +1,7 @@
 use proc_macro2::{Ident, TokenStream};
 use quote::{quote, format_ident};
 use syn::{self, Attribute, DeriveInput, parse_macro_input, punctuated::Punctuated};
-use syn::Meta::{List, NameValue};
+use syn::Meta::{List, NameValue, Path};
 use syn::NestedMeta::Meta;
 use syn::Token;
 
@@ -47,9 +47,11 @@ struct Variant<'a> {
 #[derive(Debug)]
 struct Field<'a> {
     pub member: syn::Member,
-    //pub attrs: FieldAttribute,
+    pub attrs: FieldAttribute,
     pub ty: &'a syn::Type,
     pub original: &'a syn::Field,
+    /// Whether reading and writing the field is traced, from `#[parcelable(trace)]` on the container.
+    pub trace: bool,
 }
 
 #[derive(Copy, Clone)]
@@ -69,7 +71,7 @@ impl<'a> Container<'a> {
     pub fn from_ast(
         item: &'a syn::DeriveInput,
     ) -> Option<Container<'a>> {
-        let data = match &item.data {
+        let mut data = match &item.data {
             syn::Data::Enum(data) => Data::Enum(enum_from_ast(&data.variants)),
             syn::Data::Struct(data) => {
                 let (style, fields) = struct_from_ast(&data.fields, None);
@@ -80,9 +82,61 @@ impl<'a> Container<'a> {
             }
         };
 
+        let attrs = container_attributes(&item.attrs);
+        if attrs.trace {
+            match &mut data {
+                Data::Enum(variants) => variants
+                    .iter_mut()
+                    .flat_map(|variant| variant.fields.iter_mut())
+                    .for_each(|field| field.trace = true),
+                Data::Struct(_, fields) => fields.iter_mut().for_each(|field| field.trace = true),
+            }
+        }
+        if attrs.transparent {
+            let is_plain_newtype = matches!(&data, Data::Struct(Style::Newtype, fields) if !fields[0].attrs.flatten
+                && !fields[0].attrs.nullable_string
+                && fields[0].attrs.tagged_trait.is_none());
+            if !is_plain_newtype || attrs.push_object || attrs.stable {
+                panic!("#[parcelable(transparent)] is only supported on newtype structs, without any other layout attribute");
+            }
+        }
+        if attrs.untagged && !matches!(&data, Data::Enum(variants) if variants.len() == 1) {
+            panic!("#[parcelable(untagged)] is only supported on enums with a single variant");
+        }
+        if let Data::Enum(variants) = &data {
+            let others = variants.iter().filter(|variant| variant.attrs.other).collect::<Vec<_>>();
+            if others.len() > 1 {
+                panic!("only one variant can be #[parcelable(other)]");
+            }
+            if let Some(other) = others.first() {
+                let is_i32 = matches!(other.style, Style::Newtype)
+                    && matches!(other.fields[0].ty, syn::Type::Path(p) if p.path.is_ident("i32"));
+                if !is_i32 || attrs.untagged {
+                    panic!("#[parcelable(other)] must be on a newtype variant wrapping an i32, in a tagged enum");
+                }
+            }
+        }
+        if let Some(version_field) = &attrs.version_field {
+            let fields = match &data {
+                Data::Struct(Style::Struct, fields) => fields,
+                _ => panic!("#[parcelable(version_field)] is only supported on structs with named fields"),
+            };
+            let version_index = fields
+                .iter()
+                .position(|field| matches!(&field.member, syn::Member::Named(ident) if ident == version_field))
+                .unwrap_or_else(|| panic!("no field named {} for #[parcelable(version_field)]", version_field));
+            if fields[..version_index].iter().any(|field| field.attrs.since.is_some()) {
+                panic!("#[parcelable(since)] fields must come after the version field");
+            }
+        } else if let Data::Struct(_, fields) = &data {
+            if fields.iter().any(|field| field.attrs.since.is_some()) {
+                panic!("#[parcelable(since)] requires #[parcelable(version_field)] on the struct");
+            }
+        }
+
         let item = Container {
             ident: item.ident.clone(),
-            attrs: container_attributes(&item.attrs),
+            attrs,
             data,
             _generics: &item.generics,
             _original: item,
@@ -109,10 +163,23 @@ impl<'a> Data<'a> {
 #[derive(Default)]
 struct ContainerAttribute {
     push_object: bool,
+    stable: bool,
+    untagged: bool,
+    version_field: Option<syn::Ident>,
+    trace: bool,
+    transparent: bool,
 }
 #[derive(Default)]
 struct VariantAttribute {
     discriminator: Option<i32>,
+    other: bool,
+}
+#[derive(Default, Debug)]
+struct FieldAttribute {
+    flatten: bool,
+    since: Option<i32>,
+    nullable_string: bool,
+    tagged_trait: Option<syn::Path>,
 }
 
 fn get_meta_items(attr: &syn::Attribute) -> Result<Vec<syn::NestedMeta>, ()> {
@@ -132,13 +199,30 @@ fn get_meta_items(attr: &syn::Attribute) -> Result<Vec<syn::NestedMeta>, ()> {
 }
 fn container_attributes(attrs: &[Attribute]) -> ContainerAttribute {
     let mut container_attribute = ContainerAttribute::default();
-    for meta_item in attrs.iter().flat_map(|attr| get_meta_items(attr)).flatten() {
+    for meta_item in attrs.iter().flat_map(get_meta_items).flatten() {
         match &meta_item {
             Meta(NameValue(m)) if m.path.get_ident().unwrap() == "push_object" => {
                 if let syn::Lit::Bool(b) = &m.lit {
                     container_attribute.push_object = b.value();
                 };
             }
+            Meta(Path(p)) if p.is_ident("stable") => {
+                container_attribute.stable = true;
+            }
+            Meta(Path(p)) if p.is_ident("untagged") => {
+                container_attribute.untagged = true;
+            }
+            Meta(Path(p)) if p.is_ident("trace") => {
+                container_attribute.trace = true;
+            }
+            Meta(Path(p)) if p.is_ident("transparent") => {
+                container_attribute.transparent = true;
+            }
+            Meta(NameValue(m)) if m.path.is_ident("version_field") => {
+                if let syn::Lit::Str(s) = &m.lit {
+                    container_attribute.version_field = Some(s.parse().unwrap());
+                };
+            }
             _ => {
                 panic!("unexpected parcelable attribute");
             }
@@ -149,13 +233,16 @@ fn container_attributes(attrs: &[Attribute]) -> ContainerAttribute {
 }
 fn variant_attributes(attrs: &[Attribute]) -> VariantAttribute {
     let mut variant_attribute = VariantAttribute::default();
-    for meta_item in attrs.iter().flat_map(|attr| get_meta_items(attr)).flatten() {
+    for meta_item in attrs.iter().flat_map(get_meta_items).flatten() {
         match &meta_item {
             Meta(NameValue(m)) if m.path.get_ident().unwrap() == "discriminator" => {
                 if let syn::Lit::Int(int) = &m.lit {
                     variant_attribute.discriminator = Some(int.base10_parse::<i32>().unwrap());
                 };
             }
+            Meta(Path(p)) if p.is_ident("other") => {
+                variant_attribute.other = true;
+            }
             _ => {
                 panic!("unexpected parcelable attribute");
             }
@@ -164,6 +251,34 @@ fn variant_attributes(attrs: &[Attribute]) -> VariantAttribute {
 
     variant_attribute
 }
+fn field_attributes(attrs: &[Attribute]) -> FieldAttribute {
+    let mut field_attribute = FieldAttribute::default();
+    for meta_item in attrs.iter().flat_map(get_meta_items).flatten() {
+        match &meta_item {
+            Meta(Path(p)) if p.is_ident("flatten") => {
+                field_attribute.flatten = true;
+            }
+            Meta(Path(p)) if p.is_ident("nullable_string") => {
+                field_attribute.nullable_string = true;
+            }
+            Meta(NameValue(m)) if m.path.is_ident("tagged_trait") => {
+                if let syn::Lit::Str(s) = &m.lit {
+                    field_attribute.tagged_trait = Some(s.parse().unwrap());
+                };
+            }
+            Meta(NameValue(m)) if m.path.is_ident("since") => {
+                if let syn::Lit::Int(int) = &m.lit {
+                    field_attribute.since = Some(int.base10_parse::<i32>().unwrap());
+                };
+            }
+            _ => {
+                panic!("unexpected parcelable attribute");
+            }
+        }
+    }
+
+    field_attribute
+}
 
 
 fn enum_from_ast(
@@ -214,22 +329,121 @@ fn fields_from_ast<'a>(
     fields
         .iter()
         .enumerate()
-        .map(|(i, field)| Field {
-            member: match &field.ident {
-                Some(ident) => syn::Member::Named(ident.clone()),
-                None => syn::Member::Unnamed(i.into()),
-            },
-            //attrs: field_attributes(field.attrs),
-            ty: &field.ty,
-            original: field,
+        .map(|(i, field)| {
+            let attrs = field_attributes(&field.attrs);
+            if [attrs.flatten, attrs.nullable_string, attrs.tagged_trait.is_some()]
+                .iter()
+                .filter(|&&set| set)
+                .count()
+                > 1
+            {
+                panic!("only one of #[parcelable(flatten)], #[parcelable(nullable_string)] and #[parcelable(tagged_trait)] can be used on a field");
+            }
+            Field {
+                member: match &field.ident {
+                    Some(ident) => syn::Member::Named(ident.clone()),
+                    None => syn::Member::Unnamed(i.into()),
+                },
+                attrs,
+                ty: &field.ty,
+                original: field,
+                trace: false,
+            }
         })
         .collect()
 }
 
+impl<'a> Field<'a> {
+    /// The field's name, as shown in traces
+    fn name(&self) -> String {
+        match &self.member {
+            syn::Member::Named(ident) => ident.to_string(),
+            syn::Member::Unnamed(index) => index.index.to_string(),
+        }
+    }
+
+    /// An expression deserializing this field from `parcel`, traced if the container asks for it.
+    fn deserialize_expression(&self) -> TokenStream {
+        let expression = self.untraced_deserialize_expression();
+        if !self.trace {
+            return expression;
+        }
+        let name = self.name();
+        quote! {{
+            let __start = parcel.position();
+            let __value = #expression;
+            parcel.trace_field::<Self>(#name, __start, &__value);
+            __value
+        }}
+    }
+
+    /// An expression serializing `value`, a reference to this field, into `parcel`, traced if the
+    /// container asks for it.
+    fn serialize_expression(&self, value: TokenStream) -> TokenStream {
+        let expression = self.untraced_serialize_expression(value.clone());
+        if !self.trace {
+            return expression;
+        }
+        let name = self.name();
+        quote! {{
+            let __start = parcel.position();
+            #expression;
+            parcel.trace_field::<Self>(#name, __start, &#value);
+        }}
+    }
+
+    fn untraced_deserialize_expression(&self) -> TokenStream {
+        let field_ty = self.ty;
+        if self.attrs.flatten {
+            quote! {
+                <#field_ty as ParcelableFields>::deserialize_fields(parcel)?
+            }
+        } else if self.attrs.nullable_string {
+            quote! {
+                parcel.read_str16_nullable()?
+            }
+        } else if let Some(create) = &self.attrs.tagged_trait {
+            quote! {
+                {
+                    let tag = parcel.read_i32()?;
+                    #create(tag, parcel)?
+                }
+            }
+        } else {
+            quote! {
+                <#field_ty as Parcelable>::deserialize(parcel)?
+            }
+        }
+    }
+
+    fn untraced_serialize_expression(&self, value: TokenStream) -> TokenStream {
+        if self.attrs.flatten {
+            quote! {
+                ParcelableFields::serialize_fields(&#value, parcel)?
+            }
+        } else if self.attrs.nullable_string {
+            quote! {
+                parcel.write_str16_nullable(#value.as_deref())?
+            }
+        } else if self.attrs.tagged_trait.is_some() {
+            quote! {
+                {
+                    parcel.write_i32(#value.tag())?;
+                    #value.serialize(parcel)?
+                }
+            }
+        } else {
+            quote! {
+                #value.serialize(parcel)?
+            }
+        }
+    }
+}
+
 fn build_newtype_variant(typename: &Ident, variant_name: &Ident, field: &Field) -> TokenStream {
-    let field_ty = field.ty;
+    let field_expression = field.deserialize_expression();
     quote! {{
-        #typename::#variant_name(<#field_ty as Parcelable>::deserialize(parcel)?)
+        #typename::#variant_name(#field_expression)
     }}
 }
 fn build_tuple_variant(typename: &Ident, variant_name: &Ident, fields: &[Field]) -> TokenStream {
@@ -237,12 +451,7 @@ fn build_tuple_variant(typename: &Ident, variant_name: &Ident, fields: &[Field])
         return build_newtype_variant(typename, variant_name, &fields[0]);
     }
 
-    let field_expressions = fields.iter().map(|field| {
-        let field_ty = field.ty;
-        quote! {
-            <#field_ty as Parcelable>::deserialize(parcel)?
-        }
-    });
+    let field_expressions = fields.iter().map(|field| field.deserialize_expression());
 
     quote! {{
         #typename::#variant_name(#(#field_expressions),*)
@@ -250,10 +459,10 @@ fn build_tuple_variant(typename: &Ident, variant_name: &Ident, fields: &[Field])
 }
 fn build_struct_variant(typename: &Ident, variant_name: &Ident, fields: &[Field]) -> TokenStream {
     let field_expressions = fields.iter().map(|field| {
-        let field_ty = field.ty;
         let field_name = &field.member;
+        let field_expression = field.deserialize_expression();
         quote! {
-            #field_name: <#field_ty as Parcelable>::deserialize(parcel)?
+            #field_name: #field_expression
         }
     });
 
@@ -273,7 +482,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
     let body_deserialize = match &cont.data {
         Data::Enum(variants) => {
-            let variant_arms = variants.iter().enumerate().map(|(i, variant)| {
+            let variant_arms = variants.iter().enumerate().filter(|(_, variant)| !variant.attrs.other).map(|(i, variant)| {
                 let discriminator = if let Some(discriminator) = variant.attrs.discriminator {
                     discriminator
                 } else {
@@ -285,7 +494,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
                 let block = match variant.style {
                     Style::Unit => {
                         quote! {
-                            #typename::#variant_name,
+                            #typename::#variant_name
                         }
                     },
                     Style::Newtype => {
@@ -298,24 +507,80 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
                         build_struct_variant(typename, variant_name, &variant.fields)
                     },
                 };
+                (discriminator, block)
+            }).collect::<Vec<_>>();
+
+            if cont.attrs.untagged {
+                // The only variant is read without any discriminant in front of it
+                let block = &variant_arms[0].1;
+                quote! {
+                    Ok(#block)
+                }
+            } else {
+                let variant_arms = variant_arms.iter().map(|(discriminator, block)| {
+                    quote! {
+                        #discriminator => #block,
+                    }
+                });
+                // Unknown discriminants are kept in the `other` variant, if there is one
+                let fallback_arm = match variants.iter().find(|variant| variant.attrs.other) {
+                    Some(other) => {
+                        let variant_name = &other.ident;
+                        quote! {
+                            discriminator => #typename::#variant_name(discriminator),
+                        }
+                    }
+                    None => quote! {
+                        _ => { return Err(Error::BadEnumValue); }
+                    },
+                };
+
                 quote! {
-                    #discriminator => #block
+                    Ok(match parcel.read_i32()? {
+                        #(#variant_arms)*
+                        #fallback_arm
+                    })
+                }
+            }
+        },
+        Data::Struct(Style::Struct, fields) if cont.attrs.version_field.is_some() => {
+            // Fields are read into locals in order, so that those added in later versions can be
+            // skipped, and given their default value, depending on the version read before them.
+            let version_field = cont.attrs.version_field.as_ref().unwrap();
+            let version_local = format_ident!("__field_{}", version_field);
+            let field_locals = fields.iter().map(|field| match &field.member {
+                syn::Member::Named(ident) => format_ident!("__field_{}", ident),
+                syn::Member::Unnamed(_) => unreachable!(),
+            }).collect::<Vec<_>>();
+            let field_reads = fields.iter().zip(&field_locals).map(|(field, local)| {
+                let field_expression = field.deserialize_expression();
+                if let Some(since) = field.attrs.since {
+                    quote! {
+                        let #local = if #version_local as i64 >= #since as i64 {
+                            #field_expression
+                        } else {
+                            Default::default()
+                        };
+                    }
+                } else {
+                    quote! {
+                        let #local = #field_expression;
+                    }
                 }
             });
+            let field_names = fields.iter().map(|field| &field.member);
 
             quote! {
-                Ok(match parcel.read_i32()? {
-                    #(#variant_arms)*
-                    _ => { return Err(Error::BadEnumValue); }
-                })
+                #(#field_reads)*
+                Ok(#typename{#(#field_names: #field_locals),*})
             }
         },
         Data::Struct(Style::Struct, fields) => {
             let field_expressions = fields.iter().map(|field| {
                 let field_name = &field.member;
-                let field_ty = field.ty;
+                let field_expression = field.deserialize_expression();
                 quote! {
-                    #field_name: <#field_ty as Parcelable>::deserialize(parcel)?
+                    #field_name: #field_expression
                 }
             });
 
@@ -324,12 +589,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
             }
         },
         Data::Struct(Style::Tuple, fields) => {
-            let field_expressions = fields.iter().map(|field| {
-                let field_ty = field.ty;
-                quote! {
-                    <#field_ty as Parcelable>::deserialize(parcel)?
-                }
-            });
+            let field_expressions = fields.iter().map(|field| field.deserialize_expression());
 
             quote! {
                 Ok(#typename(#(#field_expressions),*))
@@ -341,9 +601,9 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
             }
         },
         Data::Struct(Style::Newtype, fields) => {
-            let field_type = fields[0].ty;
+            let field_expression = fields[0].deserialize_expression();
             quote! {
-                Ok(#typename(<#field_type as Parcelable>::deserialize(parcel)?))
+                Ok(#typename(#field_expression))
             }
         },
     };
@@ -358,28 +618,39 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
                 };
 
                 let variant_name = &variant.ident;
+                if variant.attrs.other {
+                    return quote! {
+                        #typename::#variant_name(discriminator) => { parcel.write_i32(*discriminator)?; },
+                    };
+                }
+                let write_discriminator = if cont.attrs.untagged {
+                    quote! {}
+                } else {
+                    quote! {
+                        parcel.write_i32(#discriminator)?;
+                    }
+                };
 
                 let block = match variant.style {
                     Style::Unit => {
                         quote! {
-                            #typename::#variant_name => { parcel.write_i32(#discriminator)?; },
+                            #typename::#variant_name => { #write_discriminator },
                         }
                     },
                     Style::Newtype => {
                         //build_newtype_variant(typename, variant_name, &variant.fields[0])
+                        let field_expression = variant.fields[0].serialize_expression(quote!(_nt));
                         quote! {
                             #typename::#variant_name(_nt) => {
-                                parcel.write_i32(#discriminator)?;
-                                _nt.serialize(parcel)?
+                                #write_discriminator
+                                #field_expression
                             }
                         }
                     },
                     Style::Tuple => {
-                        let field_expressions = variant.fields.iter().enumerate().map(|(i, _field)| {
+                        let field_expressions = variant.fields.iter().enumerate().map(|(i, field)| {
                             let name = format_ident!("_t_{}", i);
-                            quote! {
-                                #name.serialize(parcel)?
-                            }
+                            field.serialize_expression(quote!(#name))
                         });
 
 
@@ -390,7 +661,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
                         quote! {
                             #typename::#variant_name(#(#field_names),*) => {
-                                parcel.write_i32(#discriminator)?;
+                                #write_discriminator
                                 #(#field_expressions);*
                             }
                         }
@@ -398,10 +669,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
                     Style::Struct => {
                         let field_expressions = variant.fields.iter().map(|field| {
                             let field_name = &field.member;
-                            quote! {
-                                #field_name.serialize(parcel)?
-                            }
-
+                            field.serialize_expression(quote!(#field_name))
                         });
                         let field_names = variant.fields.iter().map(|field| {
                             &field.member
@@ -410,7 +678,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
 
                         quote! {
                             #typename::#variant_name{#(#field_names),*} => {
-                                parcel.write_i32(#discriminator)?;
+                                #write_discriminator
 
                                 #(#field_expressions);*
                             }
@@ -429,8 +697,9 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
         Data::Struct(Style::Struct, fields) => {
             let field_expressions = fields.iter().map(|field| {
                 let field_name = &field.member;
+                let field_expression = field.serialize_expression(quote!(self.#field_name));
                 quote! {
-                    self.#field_name.serialize(parcel)?;
+                    #field_expression;
                 }
             });
 
@@ -439,10 +708,11 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
             }
         },
         Data::Struct(Style::Tuple, fields) => {
-            let field_expressions = fields.iter().enumerate().map(|(i, _field)| {
+            let field_expressions = fields.iter().enumerate().map(|(i, field)| {
                 let name = format_ident!("_t_{}", i);
+                let field_expression = field.serialize_expression(quote!(#name));
                 quote! {
-                    #name.serialize(parcel)?;
+                    #field_expression;
                 }
             });
 
@@ -463,23 +733,87 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
             quote! {
             }
         },
-        Data::Struct(Style::Newtype, _fields) => {
+        Data::Struct(Style::Newtype, fields) => {
+            let field_expression = fields[0].serialize_expression(quote!(self.0));
             quote! {
-                self.0.serialize(parcel)?;
+                #field_expression;
             }
         },
     };
 
-    let push_object_block = if cont.attrs.push_object {
+    let (push_object_block, pop_object_block) = if cont.attrs.push_object {
+        (
+            quote! {
+                parcel.push_object()?;
+            },
+            quote! {
+                parcel.pop_object()?;
+            },
+        )
+    } else {
+        (quote! {}, quote! {})
+    };
+    // Structs can be flattened into a parent, which splices in just their fields, without any
+    // envelope or object offset.
+    let fields_impl = if let Data::Struct(..) = &cont.data {
         quote! {
-            parcel.push_object()?;
+            impl ParcelableFields for #ident {
+                fn deserialize_fields(parcel: &mut Parcel) -> Result<Self, Error> where Self: Sized {
+                    #body_deserialize
+                }
+                fn serialize_fields(&self, parcel: &mut Parcel) -> Result<(), Error> {
+                    #body_serialize
+                    Ok(())
+                }
+            }
         }
     } else {
         quote! {}
     };
+
+    let (body_deserialize, body_serialize) = if cont.attrs.stable {
+        (
+            quote! {
+                parcel.read_parcelable_envelope(|parcel| {
+                    #body_deserialize
+                })
+            },
+            quote! {
+                parcel.write_parcelable_envelope(|parcel| {
+                    #body_serialize
+                    Ok(())
+                })?;
+            },
+        )
+    } else {
+        (body_deserialize, body_serialize)
+    };
+
+    // A transparent newtype is interchangeable with the type it wraps
+    let conversions = match &cont.data {
+        Data::Struct(Style::Newtype, fields) if cont.attrs.transparent => {
+            let inner_ty = fields[0].ty;
+            quote! {
+                impl From<#inner_ty> for #ident {
+                    fn from(value: #inner_ty) -> Self {
+                        #ident(value)
+                    }
+                }
+                impl From<#ident> for #inner_ty {
+                    fn from(value: #ident) -> Self {
+                        value.0
+                    }
+                }
+            }
+        }
+        _ => quote! {},
+    };
+
     let output = quote! {
+        #conversions
         impl Parcelable for #ident {
             fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> where Self: Sized {
+                #pop_object_block
                 #body_deserialize
             }
             fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
@@ -488,6 +822,7 @@ pub fn parcelable_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStr
                 Ok(())
             }
         }
+        #fields_impl
     };
 
     output.into()
@@ -1,9 +1,11 @@
-use crate::{parcel::Parcel, Error, Parcelable};
+use crate::{parcel::Parcel, Error, Parcelable, ParcelableFields};
 use parcelable_derive::Parcelable;
 
 use nix::{
+    errno::Errno,
     fcntl::{open, OFlag},
     ioctl_readwrite, ioctl_write_int, ioctl_write_ptr,
+    poll::{poll, PollFd, PollFlags},
     sys::{
         mman::{mmap, MapFlags, ProtFlags},
         stat::Mode,
@@ -12,12 +14,20 @@ use nix::{
 };
 
 use std::{
+    cell::Cell,
+    collections::HashSet,
     convert::{TryFrom, TryInto},
     ffi::c_void,
     mem::size_of,
     ops::BitOr,
     os::unix::io::RawFd,
     ptr, slice,
+    sync::Arc,
+};
+
+use std::{
+    thread::{self, ThreadId},
+    time::{Duration, Instant},
 };
 
 use num_traits::FromPrimitive;
@@ -28,8 +38,22 @@ const DEVICE: &str = "/dev/binder";
 /// The default maximum number of threads to support
 const DEFAULT_MAX_BINDER_THREADS: u32 = 15;
 
+/// How many reads returning no data at all a two-way transaction makes before failing with
+/// `NoReply`. The driver starts every read with `BR_NOOP`, so an empty one means it is misbehaving.
+const MAX_EMPTY_READS: u32 = 16;
+
+/// How many times a write/read which fails with `EAGAIN` is retried, unless changed with
+/// `Binder::set_max_eagain_retries`
+const DEFAULT_MAX_EAGAIN_RETRIES: u32 = 8;
+/// The wait before the first `EAGAIN` retry, doubled for each one after it
+const EAGAIN_INITIAL_BACKOFF: Duration = Duration::from_millis(1);
+const EAGAIN_MAX_BACKOFF: Duration = Duration::from_millis(100);
+
 const PAGE_SIZE: usize = 0x1000;
 const BINDER_VM_SIZE: usize = (1 * 1024 * 1024) - PAGE_SIZE * 2;
+/// The largest transaction, data and offsets together, that fits in the receiver's buffer. Larger
+/// payloads should be passed through shared memory, for instance with `Parcel::write_blob`.
+pub const MAX_TRANSACTION_SIZE: usize = BINDER_VM_SIZE;
 
 macro_rules! pack_chars {
     ($c1:expr, $c2:expr, $c3:expr, $c4:expr) => {
@@ -58,12 +82,11 @@ pub enum BinderType {
     Fda = TF_FDA,
     Ptr = TF_PTR,
 }
-impl Parcelable for BinderType {
-    fn deserialize(parcel: &mut Parcel) -> Result<Self, crate::Error>
-    where
-        Self: Sized,
-    {
-        Ok(match parcel.read_u32()? {
+impl TryFrom<u32> for BinderType {
+    type Error = Error;
+
+    fn try_from(binder_type: u32) -> Result<Self, Error> {
+        Ok(match binder_type {
             TF_BINDER => BinderType::Binder,
             TF_WEAKBINDER => BinderType::WeakBinder,
             TF_HANDLE => BinderType::Handle,
@@ -76,6 +99,15 @@ impl Parcelable for BinderType {
             }
         })
     }
+}
+
+impl Parcelable for BinderType {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, crate::Error>
+    where
+        Self: Sized,
+    {
+        BinderType::try_from(parcel.read_u32()?)
+    }
 
     fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
         parcel.write_u32(*self as u32)?;
@@ -112,6 +144,17 @@ impl BinderFlatObject {
         self.cookie
     }
 }
+/// The lowest scheduling priority a transaction on the object may run at
+pub const FLAT_BINDER_FLAG_PRIORITY_MASK: u32 = 0xff;
+/// The object's owner accepts file descriptors in transactions sent to it
+pub const FLAT_BINDER_FLAG_ACCEPTS_FDS: u32 = 0x100;
+/// The flags Android's `Parcel::writeFileDescriptor` sets on file descriptor objects: a minimum
+/// priority of `0x7f` and `FLAT_BINDER_FLAG_ACCEPTS_FDS`, giving `0x17f`. These are not `O_*` open
+/// flags, the fd keeps the access mode it was opened with.
+pub const FD_OBJECT_FLAGS: u32 = 0x7f | FLAT_BINDER_FLAG_ACCEPTS_FDS;
+
+/// A file descriptor object. Unlike `BinderFlatObject` this has no stability field, since only
+/// binders carry one. The fd is in `handle`, and `cookie` is 1 if the receiving parcel owns it.
 #[derive(Parcelable, Clone, Debug)]
 #[parcelable(push_object = true)]
 pub struct BinderFd {
@@ -166,6 +209,31 @@ pub enum Transaction {
     Like = LIKE_TRANSACTION,
 }
 
+impl TryFrom<u32> for Transaction {
+    type Error = Error;
+
+    fn try_from(code: u32) -> Result<Self, Error> {
+        Transaction::from_u32(code).ok_or(Error::BadEnumValue)
+    }
+}
+
+/// The status replied for transaction codes a service does not handle, `-EBADMSG`
+pub(crate) const UNKNOWN_TRANSACTION: i32 = -74;
+
+/// The status replied for calls made through another interface than the service's, `-EPERM`
+pub(crate) const PERMISSION_DENIED: i32 = -1;
+
+fn check_transaction_size(data: &Parcel) -> Result<(), Error> {
+    let size = data.len() + data.offsets_len() * size_of::<usize>();
+    if size > MAX_TRANSACTION_SIZE {
+        return Err(Error::TransactionTooLarge {
+            size,
+            max: MAX_TRANSACTION_SIZE,
+        });
+    }
+    Ok(())
+}
+
 /// A structure representing the binder version
 #[repr(C)]
 pub struct BinderVersion {
@@ -174,12 +242,12 @@ pub struct BinderVersion {
 
 #[repr(C)]
 pub struct BinderWriteRead {
-    write_size: usize,
-    write_consumed: usize,
-    write_buffer: *const c_void,
-    read_size: usize,
-    read_consumed: usize,
-    read_buffer: *mut c_void,
+    pub(crate) write_size: usize,
+    pub(crate) write_consumed: usize,
+    pub(crate) write_buffer: *const c_void,
+    pub(crate) read_size: usize,
+    pub(crate) read_consumed: usize,
+    pub(crate) read_buffer: *mut c_void,
 }
 
 impl BinderWriteRead {
@@ -207,16 +275,16 @@ pub(crate) struct BinderTransactionDataData {}
 #[repr(C)]
 #[derive(Debug)]
 pub struct BinderTransactionData {
-    target: u32,
-    cookie: u64,
-    code: u32,
-    flags: u32,
-    sender_pid: u32,
-    sender_euid: u32,
-    data_size: u64,
-    offset_size: u64,
-    data: *mut u8,
-    offsets: *mut usize,
+    pub(crate) target: u32,
+    pub(crate) cookie: u64,
+    pub(crate) code: u32,
+    pub(crate) flags: u32,
+    pub(crate) sender_pid: u32,
+    pub(crate) sender_euid: u32,
+    pub(crate) data_size: u64,
+    pub(crate) offset_size: u64,
+    pub(crate) data: *mut u8,
+    pub(crate) offsets: *mut usize,
 }
 
 impl BinderTransactionData {
@@ -244,6 +312,42 @@ impl BinderTransactionData {
     }
 }
 
+/// Matches the kernel's `binder_transaction_data`, in which the target handle is in a union with a
+/// pointer, and every pointer and size is 64 bits wide regardless of the platform.
+impl Parcelable for BinderTransactionData {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self {
+            target: parcel.read_u64()? as u32,
+            cookie: parcel.read_u64()?,
+            code: parcel.read_u32()?,
+            flags: parcel.read_u32()?,
+            sender_pid: parcel.read_u32()?,
+            sender_euid: parcel.read_u32()?,
+            data_size: parcel.read_u64()?,
+            offset_size: parcel.read_u64()?,
+            data: parcel.read_u64()? as usize as *mut u8,
+            offsets: parcel.read_u64()? as usize as *mut usize,
+        })
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_u64(self.target as u64)?;
+        parcel.write_u64(self.cookie)?;
+        parcel.write_u32(self.code)?;
+        parcel.write_u32(self.flags)?;
+        parcel.write_u32(self.sender_pid)?;
+        parcel.write_u32(self.sender_euid)?;
+        parcel.write_u64(self.data_size)?;
+        parcel.write_u64(self.offset_size)?;
+        parcel.write_u64(self.data as usize as u64)?;
+        parcel.write_u64(self.offsets as usize as u64)?;
+        Ok(())
+    }
+}
+
 enum BinderResult {
     InvalidOperation,
     NoError,
@@ -252,6 +356,7 @@ enum BinderResult {
 ioctl_readwrite!(binder_write_read, b'b', 1, BinderWriteRead);
 ioctl_write_ptr!(binder_set_max_threads, b'b', 5, u32);
 ioctl_readwrite!(binder_read_version, b'b', 9, BinderVersion);
+ioctl_write_ptr!(binder_enable_oneway_spam_detection, b'b', 16, u32);
 
 bitflags! {
     pub struct TransactionFlags: u32 {
@@ -260,6 +365,8 @@ bitflags! {
         const RootObject = 4;
         const StatusCode = 8;
         const AcceptFds = 0x10;
+        /// Ask the driver to zero the transaction buffer once it has been delivered and freed, so
+        /// that secrets such as keys or tokens do not linger in the receiver's memory.
         const ClearBuf = 0x20;
     }
 }
@@ -292,7 +399,7 @@ const BC_RELEASE: u32 = _iow!(b'c', 6, 0x4);
 const BC_DECREFS: u32 = _iow!(b'c', 7, 0x4);
 const BC_INCREFS_DONE: u32 = _iow!(b'c', 8, 0x10);
 const BC_ACQUIRE_DONE: u32 = _iow!(b'c', 9, 0x10);
-const BC_ATTEMPT_ACQUIRE: u32 = _iow!(b'c', 10, 0x10);
+const BC_ATTEMPT_ACQUIRE: u32 = _iow!(b'c', 10, 0x8);
 const BC_REGISTER_LOOPER: u32 = _io!(b'c', 11);
 const BC_ENTER_LOOPER: u32 = _io!(b'c', 12);
 const BC_EXIT_LOOPER: u32 = _io!(b'c', 13);
@@ -344,7 +451,7 @@ const BR_INCREFS: u32 = _ior!(b'r', 7, 0x10);
 const BR_ACQUIRE: u32 = _ior!(b'r', 8, 0x10);
 const BR_RELEASE: u32 = _ior!(b'r', 9, 0x10);
 const BR_DECREFS: u32 = _ior!(b'r', 10, 0x10);
-const BR_ATTEMPT_ACQUIRE: u32 = _ior!(b'r', 11, 0xc);
+const BR_ATTEMPT_ACQUIRE: u32 = _ior!(b'r', 11, 0x18);
 const BR_NOOP: u32 = _io!(b'r', 12);
 const BR_SPAWN_LOOPER: u32 = _io!(b'r', 13);
 const BR_FINISHED: u32 = _io!(b'r', 14);
@@ -355,7 +462,7 @@ const BR_FROZEN_REPLY: u32 = _io!(b'r', 18);
 const BR_ONEWAY_SPAM_SUSPECT: u32 = _io!(b'r', 19);
 
 #[repr(u32)]
-#[derive(Debug, FromPrimitive, ToPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive, ToPrimitive)]
 pub enum BinderDriverReturnProtocol {
     Error = BR_ERROR,
     Ok = BR_OK,
@@ -386,32 +493,162 @@ impl From<u32> for BinderDriverReturnProtocol {
     }
 }
 
+/// The return commands the driver sent, with the transaction or reply and its data, as returned by
+/// `Binder::do_write_read_traced`
+pub type TracedWriteRead = (Vec<BinderDriverReturnProtocol>, Option<BinderTransactionData>, Parcel);
+
+/// The number of bytes the driver returned, with the transaction or reply among them, if any
+type IncomingRead = (usize, Option<BinderTransactionData>, Parcel);
+
+/// A transaction sent with `Binder::send_transaction`, waiting for its reply
+pub(crate) struct PendingTransaction {
+    /// The latest read from the driver
+    read: Result<IncomingRead, Error>,
+    one_way: bool,
+    deadline: Option<Instant>,
+    empty_reads: u32,
+    previous_driver_status: Option<i32>,
+    #[cfg(feature = "transaction-timing")]
+    start: Instant,
+    #[cfg(feature = "transaction-timing")]
+    handle: i32,
+    #[cfg(feature = "transaction-timing")]
+    code: u32,
+}
+
+impl PendingTransaction {
+    /// When to give up waiting for the reply, if ever
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+/// The work source of a thread which has not set one, see `Binder::set_work_source_uid`
+pub const UNSET_WORK_SOURCE: i32 = -1;
+
+thread_local! {
+    static WORK_SOURCE_UID: Cell<i32> = const { Cell::new(UNSET_WORK_SOURCE) };
+}
+
+/// Restores the thread's previous work source when dropped, see `Binder::set_work_source_uid`
+#[must_use]
+#[derive(Debug)]
+pub struct WorkSourceGuard {
+    previous: i32,
+}
+
+impl Drop for WorkSourceGuard {
+    fn drop(&mut self) {
+        WORK_SOURCE_UID.with(|uid| uid.set(self.previous));
+    }
+}
+
+/// The calls a `Binder` makes to the driver to exchange commands, so that the unit tests can stand
+/// in for the device
+pub(crate) trait Transport: Send + Sync {
+    /// Make a single `BINDER_WRITE_READ` ioctl, without retrying
+    fn write_read(&self, write_read: &mut BinderWriteRead) -> nix::Result<()>;
+
+    /// Wait up to `timeout` milliseconds for incoming work, returning whether there is any
+    fn poll(&self, timeout: i32) -> nix::Result<bool>;
+
+    /// Read the driver's protocol version
+    fn version(&self) -> nix::Result<i32>;
+}
+
+/// The binder device itself
+struct Driver {
+    fd: RawFd,
+}
+
+impl Transport for Driver {
+    fn write_read(&self, write_read: &mut BinderWriteRead) -> nix::Result<()> {
+        unsafe { binder_write_read(self.fd, write_read) }?;
+        Ok(())
+    }
+
+    fn poll(&self, timeout: i32) -> nix::Result<bool> {
+        let mut poll_fds = [PollFd::new(self.fd, PollFlags::POLLIN)];
+        Ok(poll(&mut poll_fds, timeout)? > 0)
+    }
+
+    fn version(&self) -> nix::Result<i32> {
+        let mut binder_version = BinderVersion {
+            protocol_version: 0,
+        };
+        unsafe { binder_read_version(self.fd, &mut binder_version) }?;
+        Ok(binder_version.protocol_version)
+    }
+}
+
 /// Structure representing an open Binder interface.
 pub struct Binder {
     fd: RawFd,
     mem: *const c_void,
+    transport: Arc<dyn Transport>,
     pending_out_data: Parcel,
+    last_driver_status: Option<i32>,
+    oneway_spam_suspect: bool,
+    acquire_result: Option<i32>,
+    max_eagain_retries: u32,
+    // The driver keeps the looper state per thread, so this is the threads which have entered it
+    looper_threads: HashSet<ThreadId>,
+    reply_timeout: Option<Duration>,
+    // Set when a reply did not come, which may still arrive and be taken for the next one's
+    needs_reset: bool,
+    #[cfg(feature = "transaction-timing")]
+    last_transaction_latency: Option<Duration>,
 }
 
+// The fd and the read-only mapping of the driver's buffer stay valid whichever thread uses them,
+// and everything else is owned data, so a `Binder` can be moved to another thread. The driver keeps
+// per-thread state (the looper, the stack of pending transactions) for the thread making each
+// ioctl, so a `Binder` should not be moved in the middle of a transaction. Which threads have
+// entered the looper is tracked by thread, so it does not matter which one uses it afterwards.
+// It is not `Sync`: all use of the queued commands goes through `&mut self`, and sharing one
+// connection between threads needs a `Mutex`, as `ServiceManager` does.
+unsafe impl Send for Binder {}
+
 impl Binder {
     pub fn new() -> Self {
+        Self::with_max_threads(DEFAULT_MAX_BINDER_THREADS)
+    }
+
+    /// Open the binder device, allowing the driver to spawn at most `max_threads` looper threads
+    /// for this process. Clients which never serve transactions can pass 0.
+    pub fn with_max_threads(max_threads: u32) -> Self {
         let mut flags = OFlag::empty();
         flags.set(OFlag::O_RDWR, true);
         flags.set(OFlag::O_CLOEXEC, true);
 
         let fd = open(DEVICE, flags, Mode::empty()).expect("Failed to open binder device");
 
+        let binder = Self::from_fd(fd).expect("Failed to set up the binder device");
+
+        binder
+            .set_max_threads(max_threads)
+            .expect("Failed to set max threads");
+
+        binder
+    }
+
+    /// Use a binder device which is already open, such as one inherited from the process which
+    /// spawned us in sandboxes where opening the device is not allowed. This takes ownership of
+    /// `fd`, which is closed if setting up fails. The maximum number of threads is left as it was
+    /// configured on the fd, see `set_max_threads`.
+    pub fn from_fd(fd: RawFd) -> Result<Self, Error> {
         let mut binder_version = BinderVersion {
             protocol_version: 0,
         };
-        unsafe {
-            binder_read_version(fd, &mut binder_version).expect("Failed to read binder version");
+        if let Err(error) = unsafe { binder_read_version(fd, &mut binder_version) } {
+            let _ = close(fd);
+            return Err(error.into());
         }
 
         let mut flags = MapFlags::empty();
         flags.set(MapFlags::MAP_PRIVATE, true);
         flags.set(MapFlags::MAP_NORESERVE, true);
-        let mapping_address = unsafe {
+        let mapping_address = match unsafe {
             mmap(
                 ptr::null_mut(),
                 BINDER_VM_SIZE,
@@ -420,40 +657,161 @@ impl Binder {
                 fd,
                 0,
             )
-        }
-        .expect("Failed to map the binder file");
+        } {
+            Ok(mapping_address) => mapping_address,
+            Err(error) => {
+                let _ = close(fd);
+                return Err(error.into());
+            }
+        };
 
-        let binder = Self {
+        Ok(Self::with_transport(fd, mapping_address as *const _, Arc::new(Driver { fd })))
+    }
+
+    /// Set up a connection which exchanges commands through `transport`, with `mem` the start of
+    /// the mapping of the driver's buffer. `fd` is only used for the ioctls outside of `Transport`,
+    /// and is closed on drop.
+    pub(crate) fn with_transport(fd: RawFd, mem: *const c_void, transport: Arc<dyn Transport>) -> Self {
+        Self {
             fd,
-            mem: mapping_address as *const _,
+            mem,
+            transport,
             pending_out_data: Parcel::empty(),
-        };
+            last_driver_status: None,
+            oneway_spam_suspect: false,
+            acquire_result: None,
+            max_eagain_retries: DEFAULT_MAX_EAGAIN_RETRIES,
+            looper_threads: HashSet::new(),
+            reply_timeout: None,
+            needs_reset: false,
+            #[cfg(feature = "transaction-timing")]
+            last_transaction_latency: None,
+        }
+    }
+
+    /// Attribute calls made from this thread to `uid`, like Java's `Binder.setCallingWorkSourceUid`.
+    /// The uid is written into the interface token of every call until the returned guard is
+    /// dropped, which restores the previous work source.
+    pub fn set_work_source_uid(uid: i32) -> WorkSourceGuard {
+        let previous = WORK_SOURCE_UID.with(|work_source| work_source.replace(uid));
+        WorkSourceGuard { previous }
+    }
+
+    /// The uid calls from this thread are attributed to, or `UNSET_WORK_SOURCE`
+    pub fn work_source_uid() -> i32 {
+        WORK_SOURCE_UID.with(|uid| uid.get())
+    }
+
+    /// The status of the most recent `BR_ERROR` reported by the driver, such as `-ENOSPC` when the
+    /// target process has run out of transaction buffer space.
+    pub fn last_driver_status(&self) -> Option<i32> {
+        self.last_driver_status
+    }
 
+    /// The time between sending the most recent two-way transaction and receiving its reply.
+    #[cfg(feature = "transaction-timing")]
+    pub fn last_transaction_latency(&self) -> Option<Duration> {
+        self.last_transaction_latency
+    }
+
+    /// Ask the driver to report oneway transactions from this process which look like spam, see
+    /// `take_oneway_spam_suspect`. Older kernels do not support this and return `EINVAL`.
+    pub fn enable_oneway_spam_detection(&self, enable: bool) -> Result<(), Error> {
         unsafe {
-            binder_set_max_threads(fd, &DEFAULT_MAX_BINDER_THREADS)
-                .expect("Failed to set max threads");
+            binder_enable_oneway_spam_detection(self.fd, &(enable as u32))?;
         }
+        Ok(())
+    }
 
-        binder
+    /// Whether the driver has flagged a oneway transaction from this process as possible spam
+    /// (`BR_ONEWAY_SPAM_SUSPECT`) since the last call. The transaction was still delivered, but the
+    /// target's async buffer is filling up and further oneway transactions may soon fail, so the
+    /// caller should slow down. Reading the flag clears it.
+    pub fn take_oneway_spam_suspect(&mut self) -> bool {
+        std::mem::replace(&mut self.oneway_spam_suspect, false)
+    }
+
+    /// Set how many times a write/read failing with `EAGAIN` is retried, with a growing delay,
+    /// before the error is returned. 0 returns it straight away.
+    pub fn set_max_eagain_retries(&mut self, max_eagain_retries: u32) {
+        self.max_eagain_retries = max_eagain_retries;
+    }
+
+    /// Set how long a two-way transaction waits for its reply. After that, it fails with `NoReply`,
+    /// and the connection can not be used again until `reset`, as the reply may still come. `None`,
+    /// the default, waits for as long as it takes, like libbinder.
+    pub fn set_reply_timeout(&mut self, reply_timeout: Option<Duration>) {
+        self.reply_timeout = reply_timeout;
+    }
+
+    /// Set the maximum number of looper threads the driver may ask this process to spawn.
+    pub fn set_max_threads(&self, max_threads: u32) -> Result<(), Error> {
+        unsafe {
+            binder_set_max_threads(self.fd, &max_threads)?;
+        }
+        Ok(())
     }
 
-    /// Tell binder that we are entering the looper
-    pub fn enter_looper(&self) -> Result<(), Error> {
+    /// Wait up to `timeout` milliseconds for the driver to have incoming work for this thread,
+    /// returning whether there is any. A `timeout` of 0 returns immediately, -1 waits forever.
+    pub fn poll(&self, timeout: i32) -> Result<bool, Error> {
+        Ok(self.transport.poll(timeout)?)
+    }
+
+    /// Tell binder that the calling thread is entering the looper. The driver rejects a second
+    /// `BC_ENTER_LOOPER` from the same thread, so this does nothing if it has already entered it.
+    pub fn enter_looper(&mut self) -> Result<(), Error> {
+        if self.is_looper_entered() {
+            return Ok(());
+        }
+
         let mut parcel_out = Parcel::empty();
 
         parcel_out.write_i32(BinderDriverCommandProtocol::EnterLooper as i32)?;
 
-        self.write_read(&parcel_out, false);
+        self.write_read(&parcel_out, false)?;
+        self.looper_threads.insert(thread::current().id());
         Ok(())
     }
 
-    /// Tell binder that we are exiting the looper
-    fn exit_looper(&self) -> Result<(), Error> {
+    /// Whether `enter_looper` has been called from the calling thread
+    pub fn is_looper_entered(&self) -> bool {
+        self.looper_threads.contains(&thread::current().id())
+    }
+
+    /// Tell binder that the calling thread is exiting the looper
+    fn exit_looper(&mut self) -> Result<(), Error> {
         let mut parcel_out = Parcel::empty();
 
         parcel_out.write_i32(BinderDriverCommandProtocol::ExitLooper as i32)?;
 
-        self.write_read(&parcel_out, false);
+        self.write_read(&parcel_out, false)?;
+        self.looper_threads.remove(&thread::current().id());
+        Ok(())
+    }
+
+    /// Recover after a failed transaction without re-opening the device: drop any queued commands,
+    /// forget the last driver error, check that the fd still answers the driver and, if this
+    /// thread was in the looper, leave and re-enter it. After a transaction timed out, whatever the
+    /// driver has waiting for this thread, such as the late reply, is read and discarded.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.pending_out_data.reset();
+        self.last_driver_status = None;
+        self.oneway_spam_suspect = false;
+
+        self.transport.version()?;
+
+        if self.needs_reset {
+            while self.transport.poll(0)? {
+                self.write_read(&Parcel::empty(), true)?;
+            }
+            self.needs_reset = false;
+        }
+
+        if self.is_looper_entered() {
+            self.exit_looper()?;
+            self.enter_looper()?;
+        }
         Ok(())
     }
 
@@ -485,6 +843,24 @@ impl Binder {
         Ok(())
     }
 
+    /// Try to promote a weak reference to the given handle to a strong one, which only succeeds if
+    /// the object is still alive. Unlike `acquire`, this is sent right away, and returns whether the
+    /// promotion succeeded. Note that the upstream Linux driver does not implement
+    /// `BC_ATTEMPT_ACQUIRE` and fails it with `EINVAL`.
+    pub fn attempt_acquire(&mut self, handle: i32) -> Result<bool, Error> {
+        self.acquire_result = None;
+        self.pending_out_data
+            .write_u32(BinderDriverCommandProtocol::AttemptAcquire as u32)?;
+        // The priority, which is unused, and the handle
+        self.pending_out_data.write_i32(0)?;
+        self.pending_out_data.write_i32(handle)?;
+        self.do_write_read(&mut Parcel::empty())?;
+        match self.acquire_result.take() {
+            Some(result) => Ok(result != 0),
+            None => Err(Error::DeserializationError),
+        }
+    }
+
     /// Release the server side resource for the given handle. Note that this request is
     /// queued and only actually perfomed with the next outgoing transaction.
     pub fn release(&mut self, handle: i32) -> Result<(), Error> {
@@ -501,6 +877,46 @@ impl Binder {
         flags: TransactionFlags,
         data: &mut Parcel,
     ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        self.transact_with_cookie(handle, 0, code, flags, data)
+    }
+
+    /// Like `transact`, but with a caller-supplied cookie. The cookie identifies the target object
+    /// when transacting to a local binder, and is handed back to the receiver as
+    /// `BinderTransactionData::cookie` so it can route the transaction.
+    pub fn transact_with_cookie(
+        &mut self,
+        handle: i32,
+        cookie: u64,
+        code: u32,
+        flags: TransactionFlags,
+        data: &mut Parcel,
+    ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        let mut pending = self.send_transaction(handle, cookie, code, flags, data)?;
+        loop {
+            if let Some(result) = self.check_transaction(&mut pending) {
+                return result;
+            }
+            let has_work = wait_for_work(&*self.transport, pending.deadline);
+            self.read_reply(&mut pending, has_work);
+        }
+    }
+
+    /// Send a transaction, the first step of `transact_with_cookie`. Until `check_transaction`
+    /// returns its outcome, the transaction waits for its reply with `wait_for_work`, then reads it
+    /// with `read_reply`. The waiting does not need the connection, so another thread sharing it
+    /// can use it meanwhile.
+    pub(crate) fn send_transaction(
+        &mut self,
+        handle: i32,
+        cookie: u64,
+        code: u32,
+        flags: TransactionFlags,
+        data: &mut Parcel,
+    ) -> Result<PendingTransaction, Error> {
+        self.check_usable()?;
+        check_transaction_size(data)?;
+        data.validate_offsets()?;
+
         self.pending_out_data
             .write_i32(BinderDriverCommandProtocol::Transaction as i32)?;
 
@@ -508,7 +924,7 @@ impl Binder {
             target: handle as u32,
             code,
             flags: (TransactionFlags::AcceptFds | flags).bits,
-            cookie: 0,
+            cookie,
             sender_pid: 0,
             sender_euid: 0,
             data_size: data.len() as u64,
@@ -519,7 +935,7 @@ impl Binder {
                 std::ptr::null_mut()
             },
             offsets: if data.offsets_len() != 0 {
-                data.offsets().as_mut_ptr()
+                data.offsets_mut().as_mut_ptr()
             } else {
                 std::ptr::null_mut()
             },
@@ -527,7 +943,118 @@ impl Binder {
         self.pending_out_data
             .write_transaction_data(&transaction_data_out)?;
 
-        self.do_write_read(&mut Parcel::empty())
+        #[cfg(feature = "transaction-timing")]
+        let start = Instant::now();
+
+        // A BR_ERROR for this transaction means no reply is coming, so tell it apart from one left
+        // over from an earlier transaction
+        let previous_driver_status = self.last_driver_status.take();
+        let read = self.read_incoming(&mut Parcel::empty());
+
+        Ok(PendingTransaction {
+            read,
+            one_way: flags.contains(TransactionFlags::OneWay),
+            deadline: self.reply_timeout.map(|timeout| Instant::now() + timeout),
+            empty_reads: 0,
+            previous_driver_status,
+            #[cfg(feature = "transaction-timing")]
+            start,
+            #[cfg(feature = "transaction-timing")]
+            handle,
+            #[cfg(feature = "transaction-timing")]
+            code,
+        })
+    }
+
+    /// Return the outcome of `pending`, or `None` if it has to wait for its reply. The reply may
+    /// come in a later read than the one which sent the transaction, but a two-way transaction
+    /// gives up with `NoReply` when the driver reports an error for it, or returns nothing at all
+    /// too many times.
+    pub(crate) fn check_transaction(
+        &mut self,
+        pending: &mut PendingTransaction,
+    ) -> Option<Result<(Option<BinderTransactionData>, Parcel), Error>> {
+        if let Ok((bytes_read, None, _)) = &pending.read {
+            if !pending.one_way {
+                if self.last_driver_status.is_some() {
+                    pending.read = Err(Error::NoReply);
+                } else {
+                    if *bytes_read == 0 {
+                        pending.empty_reads += 1;
+                    }
+                    if pending.empty_reads < MAX_EMPTY_READS {
+                        return None;
+                    }
+                    self.needs_reset = true;
+                    pending.read = Err(Error::NoReply);
+                }
+            }
+        }
+
+        if self.last_driver_status.is_none() {
+            self.last_driver_status = pending.previous_driver_status;
+        }
+        let result = std::mem::replace(&mut pending.read, Err(Error::NoReply))
+            .map(|(_, transaction, parcel)| (transaction, parcel));
+
+        #[cfg(feature = "transaction-timing")]
+        if let Ok((Some(_), _)) = &result {
+            if !pending.one_way {
+                let latency = pending.start.elapsed();
+                log::debug!(
+                    "binder: transaction handle={} code={} latency_us={}",
+                    pending.handle,
+                    pending.code,
+                    latency.as_micros()
+                );
+                self.last_transaction_latency = Some(latency);
+            }
+        }
+
+        Some(result)
+    }
+
+    /// Read what the driver has for `pending`, after `wait_for_work` returned `has_work`. If the
+    /// wait timed out, the connection needs a `reset`, as the reply may still come and would be
+    /// taken for that of the next transaction.
+    pub(crate) fn read_reply(&mut self, pending: &mut PendingTransaction, has_work: Result<bool, Error>) {
+        pending.read = match has_work {
+            Ok(true) => self.read_incoming(&mut Parcel::empty()),
+            Ok(false) => {
+                self.needs_reset = true;
+                Err(Error::NoReply)
+            }
+            Err(error) => Err(error),
+        };
+    }
+
+    /// The transport, for waiting on the driver with `wait_for_work` without the connection
+    pub(crate) fn transport(&self) -> Arc<dyn Transport> {
+        self.transport.clone()
+    }
+
+    /// Reply to a successful call, with the status Android's clients expect in front of `data`
+    pub fn reply_ok(
+        &mut self,
+        data: &mut Parcel,
+        flags: TransactionFlags,
+    ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        let mut parcel = Parcel::empty();
+        parcel.write_no_exception()?;
+        parcel.append_parcel(data)?;
+        self.reply(&mut parcel, flags)
+    }
+
+    /// Reply to a failed call with an exception, which Android's clients throw with `message`
+    pub fn reply_error(
+        &mut self,
+        code: i32,
+        message: &str,
+        flags: TransactionFlags,
+    ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        let mut parcel = Parcel::empty();
+        parcel.write_exception(code, message)?;
+        self.reply(&mut parcel, flags)
     }
 
     pub fn reply(
@@ -535,6 +1062,9 @@ impl Binder {
         data: &mut Parcel,
         flags: TransactionFlags,
     ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        self.check_usable()?;
+        check_transaction_size(data)?;
+
         self.pending_out_data
             .write_i32(BinderDriverCommandProtocol::Reply as i32)?;
 
@@ -553,7 +1083,7 @@ impl Binder {
                 std::ptr::null_mut()
             },
             offsets: if data.offsets_len() != 0 {
-                data.offsets().as_mut_ptr()
+                data.offsets_mut().as_mut_ptr()
             } else {
                 std::ptr::null_mut()
             },
@@ -568,29 +1098,79 @@ impl Binder {
         &mut self,
         parcel_out: &mut Parcel,
     ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
+        self.check_usable()?;
+        let (_, transaction, parcel) = self.read_incoming(parcel_out)?;
+        Ok((transaction, parcel))
+    }
+
+    /// Send `parcel_out` with the queued commands and process what the driver returns, as
+    /// `do_write_read` does, also returning how many bytes the driver returned
+    fn read_incoming(&mut self, parcel_out: &mut Parcel) -> Result<IncomingRead, Error> {
         self.pending_out_data.append_parcel(parcel_out)?;
-        let mut parcel_in = self.write_read(&self.pending_out_data, true);
+        let mut parcel_in = self.write_read(&self.pending_out_data, true)?;
         self.pending_out_data.reset();
 
-        self.proccess_incoming(&mut parcel_in)
+        let (transaction, parcel) = self.proccess_incoming(&mut parcel_in, None)?;
+        Ok((parcel_in.len(), transaction, parcel))
+    }
+
+    /// Fail with `NeedsReset` if a transaction timed out since the last `reset`
+    fn check_usable(&self) -> Result<(), Error> {
+        if self.needs_reset {
+            return Err(Error::NeedsReset);
+        }
+        Ok(())
+    }
+
+    /// Like `do_write_read`, but also returns every return command the driver sent, in order, up
+    /// to and including the reply or transaction. This is for diagnosing a command stream which has
+    /// got out of sync.
+    pub fn do_write_read_traced(&mut self, parcel_out: &mut Parcel) -> Result<TracedWriteRead, Error> {
+        self.check_usable()?;
+        self.pending_out_data.append_parcel(parcel_out)?;
+        let mut parcel_in = self.write_read(&self.pending_out_data, true)?;
+        self.pending_out_data.reset();
+
+        let mut commands = vec![];
+        let (transaction, parcel) = self.proccess_incoming(&mut parcel_in, Some(&mut commands))?;
+        Ok((commands, transaction, parcel))
+    }
+
+    /// Check that the data and offsets of an incoming transaction lie within our mapping of the
+    /// driver's buffer, so that building a `Parcel` from them does not read out of bounds.
+    fn check_transaction_buffers(&self, transaction: &BinderTransactionData) -> Result<(), Error> {
+        let mapping = self.mem as usize..self.mem as usize + BINDER_VM_SIZE;
+        let in_mapping = |ptr: usize, size: u64| {
+            size == 0
+                || (mapping.contains(&ptr)
+                    && size <= BINDER_VM_SIZE as u64
+                    && ptr + size as usize <= mapping.end)
+        };
+        if transaction.offset_size & (size_of::<usize>() as u64 - 1) != 0
+            || !in_mapping(transaction.data as usize, transaction.data_size)
+            || !in_mapping(transaction.offsets as usize, transaction.offset_size)
+        {
+            return Err(Error::CorruptTransaction);
+        }
+        Ok(())
     }
 
     fn proccess_incoming(
         &mut self,
         parcel_in: &mut Parcel,
+        mut commands: Option<&mut Vec<BinderDriverReturnProtocol>>,
     ) -> Result<(Option<BinderTransactionData>, Parcel), Error> {
         while parcel_in.has_unread_data() {
             let cmd_u32 = parcel_in.read_u32()?;
             let cmd_option = BinderDriverReturnProtocol::from_u32(cmd_u32);
             if let Some(cmd) = cmd_option {
+                if let Some(commands) = commands.as_mut() {
+                    commands.push(cmd);
+                }
                 match cmd {
                     BinderDriverReturnProtocol::TransactionComplete => {}
-                    BinderDriverReturnProtocol::DeadReply => {
-                        panic!("Got a DEAD_REPLY");
-                    }
-                    BinderDriverReturnProtocol::FailedReply => {
-                        panic!("Transaction failed");
-                    }
+                    BinderDriverReturnProtocol::DeadReply => return Err(Error::DeadReply),
+                    BinderDriverReturnProtocol::FailedReply => return Err(Error::FailedReply),
                     BinderDriverReturnProtocol::IncRefs => {
                         log::info!("binder: IncRefs ******************");
                     }
@@ -598,11 +1178,22 @@ impl Binder {
                         log::info!("binder: Acquire ******************");
                     }
                     BinderDriverReturnProtocol::AcquireResult => {
-                        log::info!("binder: AcquireResult ****************");
+                        self.acquire_result = Some(parcel_in.read_i32()?);
+                    }
+                    BinderDriverReturnProtocol::AttemptAcquire => {
+                        // The priority, padding, and the pointer and cookie of the local object
                         parcel_in.read_i32()?;
+                        parcel_in.read_u32()?;
+                        parcel_in.read_usize()?;
+                        parcel_in.read_usize()?;
+                        // Our objects live as long as their listener, so the promotion succeeds
+                        self.pending_out_data
+                            .write_u32(BinderDriverCommandProtocol::AcquireResult as u32)?;
+                        self.pending_out_data.write_i32(1)?;
                     }
                     BinderDriverReturnProtocol::Reply | BinderDriverReturnProtocol::Transaction => {
                         let transaction_data_in = parcel_in.read_transaction_data()?;
+                        self.check_transaction_buffers(&transaction_data_in)?;
                         let parcel = unsafe {
                             Parcel::from_data_and_offsets(
                                 transaction_data_in.data,
@@ -614,7 +1205,13 @@ impl Binder {
                         return Ok((Some(transaction_data_in), parcel));
                     }
                     BinderDriverReturnProtocol::Error => {
-                        println!("Got an error {}", parcel_in.read_i32()?);
+                        let status = parcel_in.read_i32()?;
+                        log::error!("binder: driver reported error {}", status);
+                        self.last_driver_status = Some(status);
+                    }
+                    BinderDriverReturnProtocol::OnwaySpamSuspect => {
+                        log::warn!("binder: oneway transaction flagged as spam, slow down");
+                        self.oneway_spam_suspect = true;
                     }
                     BinderDriverReturnProtocol::Noop => {}
                     BinderDriverReturnProtocol::SpawnLooper => {}
@@ -625,8 +1222,9 @@ impl Binder {
 
         Ok((None, Parcel::empty()))
     }
-    /// Perform a low-level binder write/read operation
-    fn write_read(&self, data_out: &Parcel, with_read: bool) -> Parcel {
+    /// Perform a low-level binder write/read operation. The operation is retried if it is
+    /// interrupted by a signal, any other driver error is returned.
+    fn write_read(&self, data_out: &Parcel, with_read: bool) -> Result<Parcel, Error> {
         let mut data_in = [0u8; 32 * 8];
 
         let mut write_read_struct = BinderWriteRead {
@@ -638,11 +1236,61 @@ impl Binder {
             read_consumed: 0,
         };
 
-        unsafe {
-            binder_write_read(self.fd, &mut write_read_struct)
-                .expect("Failed to perform write_read");
+        self.ioctl_write_read(&mut write_read_struct)?;
+        let mut parcel_in = Parcel::from_slice(&data_in[..write_read_struct.read_consumed]);
+
+        // A full read buffer means the driver may have more return commands queued for us. Drain
+        // them, without blocking once there is nothing left, so that none are lost.
+        while with_read && write_read_struct.read_consumed == data_in.len() && self.poll(0)? {
+            write_read_struct.write_size = 0;
+            write_read_struct.write_consumed = 0;
+            write_read_struct.read_consumed = 0;
+            self.ioctl_write_read(&mut write_read_struct)?;
+            parcel_in.set_position(parcel_in.len() as u64);
+            parcel_in.append_parcel(&mut Parcel::from_slice(&data_in[..write_read_struct.read_consumed]))?;
+        }
+
+        parcel_in.set_position(0);
+        Ok(parcel_in)
+    }
+
+    /// Run a write/read ioctl. It is retried for as long as it is interrupted by a signal, and with
+    /// an exponential backoff while the driver reports `EAGAIN`, up to `max_eagain_retries` times.
+    /// The driver updates the consumed counts before failing, so a retry carries on where it stopped.
+    fn ioctl_write_read(&self, write_read_struct: &mut BinderWriteRead) -> Result<(), Error> {
+        let mut eagain_retries = 0;
+        let mut backoff = EAGAIN_INITIAL_BACKOFF;
+        loop {
+            match self.transport.write_read(write_read_struct) {
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(nix::Error::Sys(Errno::EAGAIN)) if eagain_retries < self.max_eagain_retries => {
+                    eagain_retries += 1;
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(EAGAIN_MAX_BACKOFF);
+                }
+                Err(error) => return Err(error.into()),
+                Ok(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Wait until the driver has work for this thread, or `deadline` has passed, returning whether
+/// there is any. Without a deadline, this waits for as long as it takes.
+pub(crate) fn wait_for_work(transport: &dyn Transport, deadline: Option<Instant>) -> Result<bool, Error> {
+    loop {
+        let timeout = match deadline {
+            // Round up, so as not to poll with no timeout just before the deadline
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32,
+                None => return Ok(false),
+            },
+            None => -1,
+        };
+        match transport.poll(timeout) {
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            result => return Ok(result?),
         }
-        Parcel::from_slice(&data_in[..write_read_struct.read_consumed])
     }
 }
 
@@ -651,8 +1299,512 @@ impl Drop for Binder {
     fn drop(&mut self) {
         //TODO: do we need to unmap?
 
-        self.exit_looper().unwrap();
+        // `BC_EXIT_LOOPER` applies to the thread sending it, so only the dropping thread's looper can
+        // be exited. The driver releases the other threads' when the fd is closed.
+        if self.is_looper_entered() {
+            self.exit_looper().unwrap();
+        }
 
         close(self.fd).unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockDriver;
+    use nix::unistd::dup;
+
+    fn reply(value: i32) -> Parcel {
+        let mut reply = Parcel::empty();
+        reply.write_i32(value).unwrap();
+        reply
+    }
+
+    #[test]
+    fn write_read_retries_eintr() {
+        let (driver, mut binder) = MockDriver::new();
+        driver.fail_next(Errno::EINTR);
+        driver.queue_reply(&reply(42));
+
+        let (_, mut parcel) = binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+        // The transaction is only sent once
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].code, 2);
+        assert!(sent[0].flags.contains(TransactionFlags::AcceptFds));
+        assert_eq!(sent[0].data.read_i32().unwrap(), 1);
+    }
+
+    #[test]
+    fn write_read_returns_hard_errors() {
+        let (driver, mut binder) = MockDriver::new();
+        driver.fail_next(Errno::EBADF);
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::Nix(nix::Error::Sys(Errno::EBADF)))
+        ));
+    }
+
+    #[test]
+    fn dead_and_failed_replies_are_errors() {
+        let (driver, mut binder) = MockDriver::new();
+        driver.queue_reply_command(BinderDriverReturnProtocol::DeadReply);
+        driver.queue_reply_command(BinderDriverReturnProtocol::FailedReply);
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::DeadReply)
+        ));
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::FailedReply)
+        ));
+    }
+
+    #[test]
+    fn transact_works_after_reset() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.enter_looper().unwrap();
+        driver.queue_reply_command(BinderDriverReturnProtocol::FailedReply);
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::FailedReply)
+        ));
+
+        // A queued command is dropped by the reset, and the looper is entered again
+        binder.add_ref(1).unwrap();
+        driver.take_sent();
+        binder.reset().unwrap();
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(
+            commands,
+            [BinderDriverCommandProtocol::ExitLooper as u32, BinderDriverCommandProtocol::EnterLooper as u32]
+        );
+        assert!(binder.is_looper_entered());
+
+        driver.queue_reply(&reply(42));
+        let (_, mut parcel) = binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [BinderDriverCommandProtocol::Transaction as u32]);
+    }
+
+    #[test]
+    fn missing_reply_times_out() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.set_reply_timeout(Some(Duration::from_millis(50)));
+        for _ in 0..4 {
+            driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        }
+
+        let start = Instant::now();
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::NoReply)
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // The reply could still come, so the connection is unusable until reset
+        driver.take_sent();
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::NeedsReset)
+        ));
+        assert!(driver.take_sent().is_empty());
+
+        // Whatever the driver still has for the thread is discarded by the reset
+        driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        binder.reset().unwrap();
+        driver.queue_reply(&reply(42));
+        let (_, mut parcel) = binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+    }
+
+    #[test]
+    fn empty_reads_are_bounded() {
+        let (driver, mut binder) = MockDriver::new();
+        assert_eq!(binder.reply_timeout, None);
+        for _ in 0..MAX_EMPTY_READS {
+            driver.queue_read(vec![]);
+        }
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::NoReply)
+        ));
+    }
+
+    #[test]
+    #[ignore = "needs /dev/binder"]
+    fn set_max_threads_on_the_device() {
+        let mut binder = Binder::with_max_threads(0);
+        binder.set_max_threads(4).unwrap();
+        // The connection is still usable afterwards, pinging the service manager
+        binder.transact(0, Transaction::Ping as u32, TransactionFlags::empty(), &mut Parcel::empty()).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs /dev/binder"]
+    fn from_fd_on_a_dup_of_the_device() {
+        let binder = Binder::with_max_threads(0);
+        let mut dup = Binder::from_fd(dup(binder.fd).unwrap()).unwrap();
+        dup.transact(0, Transaction::Ping as u32, TransactionFlags::empty(), &mut Parcel::empty()).unwrap();
+    }
+
+    #[test]
+    fn from_fd_rejects_other_files() {
+        let fd = open("/dev/null", OFlag::O_RDWR, Mode::empty()).unwrap();
+        assert!(Binder::from_fd(fd).is_err());
+    }
+
+    #[test]
+    fn cloned_request_can_be_sent_again() {
+        let (driver, mut binder) = MockDriver::new();
+        let mut request = Parcel::empty();
+        request.write_str16("request").unwrap();
+        request.write_binder(3 as *const c_void).unwrap();
+        let mut retry = request.clone();
+        driver.queue_reply(&reply(1));
+        driver.queue_reply(&reply(2));
+
+        binder.transact(1, 2, TransactionFlags::empty(), &mut request).unwrap();
+        binder.transact(1, 2, TransactionFlags::empty(), &mut retry).unwrap();
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].data, sent[1].data);
+        assert_eq!(sent[1].data.offsets(), request.offsets());
+    }
+
+    #[test]
+    fn transaction_data_kernel_layout() {
+        let transaction = BinderTransactionData {
+            target: 1,
+            cookie: 0x0203_0405_0607_0809,
+            code: 0x0a0b_0c0d,
+            flags: 0x10,
+            sender_pid: 0x11,
+            sender_euid: 0x12,
+            data_size: 0x13,
+            offset_size: 0x14,
+            data: 0x15 as *mut u8,
+            offsets: 0x16 as *mut usize,
+        };
+        let mut parcel = Parcel::empty();
+        parcel.write_transaction_data(&transaction).unwrap();
+
+        // `struct binder_transaction_data` on a 64-bit kernel, in little endian
+        #[rustfmt::skip]
+        let expected: [u8; 64] = [
+            1, 0, 0, 0, 0, 0, 0, 0, // target.handle, in a 64-bit union with target.ptr
+            9, 8, 7, 6, 5, 4, 3, 2, // cookie
+            0x0d, 0x0c, 0x0b, 0x0a, // code
+            0x10, 0, 0, 0, // flags
+            0x11, 0, 0, 0, // sender_pid
+            0x12, 0, 0, 0, // sender_euid
+            0x13, 0, 0, 0, 0, 0, 0, 0, // data_size
+            0x14, 0, 0, 0, 0, 0, 0, 0, // offsets_size
+            0x15, 0, 0, 0, 0, 0, 0, 0, // data.ptr.buffer
+            0x16, 0, 0, 0, 0, 0, 0, 0, // data.ptr.offsets
+        ];
+        assert_eq!(parcel.to_slice(), expected);
+
+        parcel.set_position(0);
+        let read = parcel.read_transaction_data().unwrap();
+        assert_eq!((read.target, read.cookie, read.code()), (1, 0x0203_0405_0607_0809, 0x0a0b_0c0d));
+        assert_eq!((read.data, read.offsets), (transaction.data, transaction.offsets));
+    }
+
+    #[test]
+    fn cookie_is_sent_and_received() {
+        let (driver, mut binder) = MockDriver::new();
+        driver.queue_reply(&reply(1));
+        binder.transact_with_cookie(1, 0x42, 2, TransactionFlags::empty(), &mut Parcel::empty()).unwrap();
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].cookie, 0x42);
+
+        driver.queue_transaction(3, 0x43, TransactionFlags::OneWay, &Parcel::empty());
+        let (transaction, _) = binder.do_write_read(&mut Parcel::empty()).unwrap();
+        assert_eq!(transaction.unwrap().cookie(), 0x43);
+    }
+
+    #[test]
+    fn driver_error_is_kept() {
+        let (driver, mut binder) = MockDriver::new();
+        // What the driver sends when it can not allocate the reply buffer, -ENOSPC
+        let mut error = Parcel::empty();
+        error.write_u32(BinderDriverReturnProtocol::Error as u32).unwrap();
+        error.write_i32(-28).unwrap();
+        driver.queue_read(error.into_vec());
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::NoReply)
+        ));
+        assert_eq!(binder.last_driver_status(), Some(-28));
+    }
+
+    #[test]
+    fn enter_looper_is_sent_once() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.enter_looper().unwrap();
+        binder.enter_looper().unwrap();
+        assert!(binder.is_looper_entered());
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [BinderDriverCommandProtocol::EnterLooper as u32]);
+
+        // Only a looper which was entered is exited
+        drop(binder);
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [BinderDriverCommandProtocol::ExitLooper as u32]);
+        let (driver, binder) = MockDriver::new();
+        drop(binder);
+        assert!(driver.take_sent().is_empty());
+    }
+
+    #[test]
+    fn corrupt_offsets_are_not_sent() {
+        let (driver, mut binder) = MockDriver::new();
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        let end = parcel.len();
+        parcel.offsets_mut().push(end);
+        assert!(matches!(parcel.validate_offsets(), Err(Error::CorruptOffsets(offset)) if offset == end));
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut parcel),
+            Err(Error::CorruptOffsets(_))
+        ));
+        assert!(driver.take_sent().is_empty());
+    }
+
+    #[test]
+    fn full_read_is_drained() {
+        let (driver, binder) = MockDriver::new();
+        // One more command than fits in a single read
+        for _ in 0..65 {
+            driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        }
+
+        let parcel = binder.write_read(&Parcel::empty(), true).unwrap();
+        assert_eq!(parcel.len(), 65 * 4);
+        assert_eq!(parcel.position(), 0);
+    }
+
+    #[test]
+    fn reply_split_across_two_reads() {
+        let (driver, mut binder) = MockDriver::new();
+        // With the transaction complete these fill the first read, so the reply comes in the second
+        for _ in 0..63 {
+            driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        }
+        driver.queue_reply(&reply(42));
+
+        let (_, mut parcel) = binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+    }
+
+    #[test]
+    fn oversized_transaction_is_not_sent() {
+        let (driver, mut binder) = MockDriver::new();
+        let mut data = Parcel::empty();
+        data.write(&vec![0; 2 * 1024 * 1024]).unwrap();
+
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut data),
+            Err(Error::TransactionTooLarge { size, max: MAX_TRANSACTION_SIZE }) if size == 2 * 1024 * 1024
+        ));
+        assert!(matches!(
+            binder.reply(&mut data, TransactionFlags::empty()),
+            Err(Error::TransactionTooLarge { .. })
+        ));
+        assert!(driver.take_sent().is_empty());
+    }
+
+    #[test]
+    fn malformed_offset_size_is_rejected() {
+        let (driver, mut binder) = MockDriver::new();
+        let corrupt_reply = |data: *mut u8, offset_size: u64| {
+            let transaction = BinderTransactionData {
+                target: 0,
+                cookie: 0,
+                code: 0,
+                flags: 0,
+                sender_pid: 0,
+                sender_euid: 0,
+                data_size: 4,
+                offset_size,
+                data,
+                offsets: data as *mut usize,
+            };
+            let mut work = Parcel::empty();
+            work.write_u32(BinderDriverReturnProtocol::Reply as u32).unwrap();
+            transaction.serialize(&mut work).unwrap();
+            work.into_vec()
+        };
+
+        // Not a whole number of offsets
+        driver.queue_read(corrupt_reply(binder.mem as *mut u8, 3));
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::CorruptTransaction)
+        ));
+        // Outside the mapping
+        binder.reset().unwrap();
+        driver.queue_read(corrupt_reply((binder.mem as usize + BINDER_VM_SIZE) as *mut u8, 0));
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::CorruptTransaction)
+        ));
+    }
+
+    #[test]
+    fn error_reply_carries_the_code_and_message() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.reply_error(-3, "bad argument", TransactionFlags::empty()).unwrap();
+        binder.reply_ok(&mut reply(7), TransactionFlags::empty()).unwrap();
+
+        // Decoded as a client reads the status in front of a reply
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        let error = &mut sent[0].data;
+        assert_eq!(error.read_i32().unwrap(), -3);
+        assert_eq!(error.read_str16().unwrap(), "bad argument");
+        assert_eq!(error.read_i32().unwrap(), 0);
+        let ok = &mut sent[1].data;
+        assert_eq!(ok.read_i32().unwrap(), 0);
+        assert_eq!(ok.read_i32().unwrap(), 7);
+        assert!(!ok.has_unread_data());
+    }
+
+    #[test]
+    #[cfg(feature = "transaction-timing")]
+    fn latency_is_recorded() {
+        let (driver, mut binder) = MockDriver::new();
+        assert_eq!(binder.last_transaction_latency(), None);
+        driver.set_read_delay(Duration::from_millis(20));
+        driver.queue_reply(&reply(42));
+
+        binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert!(binder.last_transaction_latency().unwrap() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn oneway_spam_suspect_is_surfaced() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.transact(1, 2, TransactionFlags::OneWay, &mut reply(1)).unwrap();
+        assert!(!binder.take_oneway_spam_suspect());
+
+        driver.queue_read((BinderDriverReturnProtocol::OnwaySpamSuspect as u32).to_ne_bytes().to_vec());
+        binder.transact(1, 2, TransactionFlags::OneWay, &mut reply(1)).unwrap();
+        assert!(binder.take_oneway_spam_suspect());
+        // Taking it clears it
+        assert!(!binder.take_oneway_spam_suspect());
+    }
+
+    #[test]
+    fn attempt_acquire_reports_the_result() {
+        let (driver, mut binder) = MockDriver::new();
+        let acquire_result = |result: i32| {
+            let mut read = Parcel::empty();
+            read.write_u32(BinderDriverReturnProtocol::AcquireResult as u32).unwrap();
+            read.write_i32(result).unwrap();
+            read.into_vec()
+        };
+
+        driver.queue_read(acquire_result(1));
+        assert!(binder.attempt_acquire(4).unwrap());
+        driver.queue_read(acquire_result(0));
+        assert!(!binder.attempt_acquire(4).unwrap());
+        // No result at all
+        driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        assert!(binder.attempt_acquire(4).is_err());
+
+        let attempt_acquire = BinderDriverCommandProtocol::AttemptAcquire as u32;
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [attempt_acquire; 3]);
+        // The size encoded in the command covers the priority and the handle
+        assert_eq!((attempt_acquire >> 16) & 0x3fff, 8);
+    }
+
+    #[test]
+    fn eagain_is_retried_a_bounded_number_of_times() {
+        let (driver, mut binder) = MockDriver::new();
+        binder.set_max_eagain_retries(3);
+        for _ in 0..3 {
+            driver.fail_next(Errno::EAGAIN);
+        }
+        driver.queue_reply(&reply(42));
+        let (_, mut parcel) = binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+        assert_eq!(driver.take_transactions().len(), 1);
+
+        for _ in 0..4 {
+            driver.fail_next(Errno::EAGAIN);
+        }
+        assert!(matches!(
+            binder.transact(1, 2, TransactionFlags::empty(), &mut reply(1)),
+            Err(Error::Nix(nix::Error::Sys(Errno::EAGAIN)))
+        ));
+    }
+
+    #[test]
+    fn traced_write_read_lists_the_commands() {
+        let (driver, mut binder) = MockDriver::new();
+        let request = reply(1);
+        let transaction = BinderTransactionData {
+            target: 1,
+            cookie: 0,
+            code: 2,
+            flags: 0,
+            sender_pid: 0,
+            sender_euid: 0,
+            data_size: request.len() as u64,
+            offset_size: 0,
+            data: request.as_ptr() as *mut u8,
+            offsets: ptr::null_mut(),
+        };
+        let mut parcel_out = Parcel::empty();
+        parcel_out.write_u32(BinderDriverCommandProtocol::Transaction as u32).unwrap();
+        transaction.serialize(&mut parcel_out).unwrap();
+        driver.queue_read((BinderDriverReturnProtocol::Noop as u32).to_ne_bytes().to_vec());
+        driver.queue_reply(&reply(42));
+
+        let (commands, transaction, mut parcel) = binder.do_write_read_traced(&mut parcel_out).unwrap();
+        assert_eq!(
+            commands,
+            [
+                BinderDriverReturnProtocol::Noop,
+                BinderDriverReturnProtocol::TransactionComplete,
+                BinderDriverReturnProtocol::Reply
+            ]
+        );
+        assert!(transaction.is_some());
+        assert_eq!(parcel.read_i32().unwrap(), 42);
+    }
+
+    #[test]
+    fn looper_is_entered_per_thread() {
+        let (driver, mut binder) = MockDriver::new();
+        thread::scope(|scope| {
+            scope.spawn(|| binder.enter_looper().unwrap());
+        });
+        assert!(!binder.is_looper_entered());
+        binder.enter_looper().unwrap();
+        assert!(binder.is_looper_entered());
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [BinderDriverCommandProtocol::EnterLooper as u32; 2]);
+
+        // Dropped on a thread which never entered the looper, there is nothing to exit
+        let (driver, mut binder) = MockDriver::new();
+        thread::scope(|scope| {
+            scope.spawn(|| binder.enter_looper().unwrap());
+        });
+        driver.take_sent();
+        drop(binder);
+        assert!(driver.take_sent().is_empty());
+    }
+}
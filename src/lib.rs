@@ -17,18 +17,70 @@ pub use parcelable::*;
 mod service;
 pub use service::*;
 
+#[cfg(test)]
+mod testing;
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("stdio error")]
     StdioError(#[from] std::io::Error),
+    #[error("system call error: {0}")]
+    Nix(#[from] nix::Error),
     #[error("utf error")]
     Utf16Error(#[from] std::string::FromUtf16Error),
     #[error("utf error")]
+    Utf16DecodeError(#[from] std::char::DecodeUtf16Error),
+    #[error("utf error")]
     Utf8Error(#[from] std::string::FromUtf8Error),
     #[error("deserialization error")]
     DeserializationError,
     #[error("bad enum value")]
     BadEnumValue,
+    #[error("invalid UTF-16 character: {0:#x}")]
+    InvalidChar(u32),
+    #[error("invalid length: {0}")]
+    InvalidLength(i32),
+    #[error("string of length {0} is too long for a parcel")]
+    StringTooLong(usize),
+    #[error("blob of length {0} is too long for a parcel")]
+    BlobTooLong(usize),
+    #[error("no object at position {position}")]
+    NotAnObject { position: u64 },
+    #[error("expected a {expected:?} object, found a {found:?}")]
+    UnexpectedObjectType {
+        expected: BinderType,
+        found: BinderType,
+    },
+    #[error("corrupt object offset: {0}")]
+    CorruptOffsets(usize),
+    #[error("objects are nested too deeply")]
+    RecursionLimitExceeded,
+    #[error("no parcelable registered for class {0:?}")]
+    UnknownParcelableClass(String),
+    #[error("unsupported value tag: {0}")]
+    UnsupportedValueTag(i32),
+    #[error("transaction of {size} bytes exceeds the maximum of {max}, consider Parcel::write_blob")]
+    TransactionTooLarge { size: usize, max: usize },
+    #[error("no reply to the transaction")]
+    NoReply,
+    #[error("a transaction timed out, the connection must be reset")]
+    NeedsReset,
+    #[error("the target of the transaction has died")]
+    DeadReply,
+    #[error("the transaction failed")]
+    FailedReply,
+    #[error("corrupt transaction buffers")]
+    CorruptTransaction,
+    #[error("invalid dump priority: {0:#x}")]
+    InvalidDumpPriority(u32),
+    #[error("interface mismatch: expected {expected}, got {actual}")]
+    InterfaceMismatch { expected: String, actual: String },
+}
+
+impl From<nix::errno::Errno> for Error {
+    fn from(errno: nix::errno::Errno) -> Self {
+        Error::Nix(nix::Error::Sys(errno))
+    }
 }
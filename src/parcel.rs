@@ -1,26 +1,127 @@
 use std::{
-    ffi::c_void,
+    any::Any,
+    convert::TryFrom,
+    ffi::{c_void, CStr},
     fmt,
+    fs::File,
+    hash::{Hash, Hasher},
     io::{Cursor, Read, Write},
     mem::size_of,
     mem::transmute,
-    os::unix::io::RawFd,
+    os::unix::{
+        fs::FileExt,
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
     slice,
 };
 
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 
-use crate::{Binder, BinderFlatObject, BinderTransactionData, BinderType, Error, Parcelable};
+use crate::{
+    Binder, BinderFd, BinderFlatObject, BinderTransactionData, BinderType, Error, Parcelable,
+    ParcelableRegistry, Value, FD_OBJECT_FLAGS,
+};
+
+/// Trace a single parcel operation. Only emits anything when the `parcel-trace` feature is enabled,
+/// so that the hot read/write paths stay free of logging overhead by default.
+#[cfg(feature = "parcel-trace")]
+macro_rules! parcel_trace {
+    ($($arg:tt)*) => {
+        trace!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "parcel-trace"))]
+macro_rules! parcel_trace {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    };
+}
+
+/// The driver rejects objects whose offset in the buffer is not aligned to a `u32`
+const OBJECT_ALIGNMENT: u64 = 4;
+
+/// The size of the objects the driver translates, `flat_binder_object` and fd objects alike
+const FLAT_OBJECT_SIZE: usize = 24;
+
+/// How deeply nested objects may be when reading, unless changed with `Parcel::set_max_depth`
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// `writeValue` type tags, which precede each value, such as the elements of a boxed `List`
+const VAL_NULL: i32 = -1;
+const VAL_STRING: i32 = 0;
+const VAL_INTEGER: i32 = 1;
+const VAL_LONG: i32 = 6;
+const VAL_DOUBLE: i32 = 8;
+const VAL_BOOLEAN: i32 = 9;
+const VAL_BYTEARRAY: i32 = 13;
+
+/// The status which marks a noted app-ops header, ahead of the real status in a reply
+const EX_HAS_NOTED_APPOPS_REPLY_HEADER: i32 = -127;
 
 const STRICT_MODE_PENALTY_GATHER: i32 = 1 << 31;
 /// The header marker, packed["S", "Y", "S", "T"];
 const HEADER: i32 = 0x53595354;
 
+/// Blobs up to this size are written inline into the parcel, larger ones go through shared memory
+const BLOB_INPLACE_LIMIT: usize = 16 * 1024;
+/// The blob data follows inline in the parcel
+const BLOB_INPLACE: i32 = 0;
+/// The blob data is in an immutable shared memory region, passed as a file descriptor
+const BLOB_ASHMEM_IMMUTABLE: i32 = 1;
+/// The blob data is in a mutable shared memory region, passed as a file descriptor
+const BLOB_ASHMEM_MUTABLE: i32 = 2;
+
+/// The length prefix of a string `len` long, which must fit in a non-negative `i32`, as a negative
+/// length would be read back as a null string
+fn string_length(len: usize) -> Result<i32, Error> {
+    i32::try_from(len).map_err(|_| Error::StringTooLong(len))
+}
+
+/// The length prefix of a blob `len` bytes long, which like a string's must fit in an `i32`
+fn blob_length(len: usize) -> Result<i32, Error> {
+    i32::try_from(len).map_err(|_| Error::BlobTooLong(len))
+}
+
+/// Marks a placeholder `u32` written by `Parcel::write_placeholder_u32`, to be filled in later
+/// with `Parcel::patch_u32`.
+#[derive(Debug)]
+#[must_use]
+pub struct PlaceholderToken {
+    position: u64,
+}
+
+/// A snapshot of a parcel's data and object offsets, made with `Parcel::capture`. With the `serde`
+/// feature it can be saved, for instance as JSON, and replayed later with `Parcel::from_capture`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CapturedParcel {
+    pub data: Vec<u8>,
+    pub offsets: Vec<usize>,
+}
+
 /// Represents a binder serializable parcel
+///
+/// Values, including the UTF-16 code units of strings, are always encoded little endian, whatever
+/// the host's byte order is.
+///
+/// A parcel has a single cursor, which both reads and writes start from. Its position is
+/// independent of the length of the data: after writing, the cursor is at the end and must be
+/// moved back with `set_position` before reading, and writing with the cursor in the middle of
+/// the data overwrites what is there rather than appending.
+///
+/// Cloning a parcel makes a deep copy of its data and object offsets, and preserves the read
+/// cursor, so a request can be kept around and re-sent. Any pointers embedded in the data (such as
+/// those in a `BinderTransactionData`) are copied as-is, so clones are meant for parcels built by
+/// the caller, not for those which reference driver buffers.
+#[derive(Clone)]
 pub struct Parcel {
     cursor: Cursor<Vec<u8>>,
     object_offsets: Vec<usize>,
     objects_position: usize,
+    depth: usize,
+    max_depth: usize,
 }
 
 impl fmt::Debug for Parcel {
@@ -31,6 +132,23 @@ impl fmt::Debug for Parcel {
             .finish()
     }
 }
+/// Parcels compare equal when their data and object offsets are equal, regardless of where their
+/// read cursors are.
+impl PartialEq for Parcel {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_slice() == other.to_slice() && self.object_offsets == other.object_offsets
+    }
+}
+
+impl Eq for Parcel {}
+
+impl Hash for Parcel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_slice().hash(state);
+        self.object_offsets.hash(state);
+    }
+}
+
 impl Parcel {
     /// Create a new empty parcel.
     pub fn empty() -> Self {
@@ -39,6 +157,8 @@ impl Parcel {
             cursor: Cursor::new(data),
             object_offsets: vec![],
             objects_position: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
@@ -48,6 +168,19 @@ impl Parcel {
             cursor: Cursor::new(data.to_vec()),
             object_offsets: vec![],
             objects_position: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    /// Create a parcel from its data and object offsets, such as those returned by `into_parts`
+    pub fn from_parts(data: Vec<u8>, object_offsets: Vec<usize>) -> Self {
+        Self {
+            cursor: Cursor::new(data),
+            object_offsets,
+            objects_position: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
@@ -61,6 +194,8 @@ impl Parcel {
             cursor: Cursor::new(slice::from_raw_parts(data, data_size).to_vec()),
             object_offsets: slice::from_raw_parts(offsets, offsets_size).to_vec(),
             objects_position: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
         }
     }
 
@@ -69,23 +204,68 @@ impl Parcel {
         self.cursor.get_mut().clear();
         self.objects_position = 0;
         self.object_offsets.clear();
+        self.depth = 0;
+    }
+
+    /// Set how deeply nested objects may be when reading from this parcel, past which reads fail
+    /// with `Error::RecursionLimitExceeded`. This bounds the stack used to deserialize untrusted
+    /// data.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Read a nested object with `read`, counting it towards the parcel's maximum depth
+    pub fn read_nested<T, F>(&mut self, read: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<T, Error>,
+    {
+        if self.depth >= self.max_depth {
+            return Err(Error::RecursionLimitExceeded);
+        }
+        self.depth += 1;
+        let result = read(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Empty the parcel while keeping its allocated capacity, so that it can be reused for the
+    /// next transaction instead of allocating a new one. This is the same as `reset`.
+    pub fn clear(&mut self) {
+        self.reset()
+    }
+
+    /// The number of bytes the parcel can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.cursor.get_ref().capacity()
     }
 
     pub fn position(&self) -> u64 {
         self.cursor.position()
     }
 
+    /// Move the cursor to `pos`. The next object to be read becomes the first one at or after
+    /// `pos`, so objects are still read in order after seeking either way.
     pub fn set_position(&mut self, pos: u64) {
-        self.cursor.set_position(pos)
+        self.cursor.set_position(pos);
+        self.seek_objects();
     }
 
-    /// Append the contents of another parcel to this parcel
+    /// Append the contents of another parcel to this parcel. Like every write, this happens at the
+    /// cursor, which is only the end of the data if nothing has been read since writing. If an
+    /// object of the other parcel would land on or within an object of this one, nothing is
+    /// appended and `Error::CorruptOffsets` is returned.
     pub fn append_parcel(&mut self, other: &mut Parcel) -> Result<(), Error> {
-        let current_position = self.cursor.position();
-        self.cursor.write_all(other.to_slice())?;
-        for offset in &other.object_offsets {
-            self.object_offsets.push(offset + current_position as usize);
+        let current_position = self.cursor.position() as usize;
+        let mut offsets = self.object_offsets.clone();
+        offsets.extend(other.object_offsets.iter().map(|offset| offset + current_position));
+        // The driver requires the offsets in ascending order, which they are no longer if the
+        // other parcel was appended before objects already in this one.
+        offsets.sort_unstable();
+        if let Some(pair) = offsets.windows(2).find(|pair| pair[1] < pair[0] + FLAT_OBJECT_SIZE) {
+            return Err(Error::CorruptOffsets(pair[1]));
         }
+        self.cursor.write_all(other.to_slice())?;
+        self.object_offsets = offsets;
         Ok(())
     }
 
@@ -103,7 +283,70 @@ impl Parcel {
     pub fn to_slice(&self) -> &[u8] {
         self.cursor.get_ref()
     }
-    /// Retrieve the size of the parcel's data
+
+    /// Take a snapshot of the parcel's data and object offsets
+    pub fn capture(&self) -> CapturedParcel {
+        CapturedParcel {
+            data: self.to_slice().to_vec(),
+            offsets: self.object_offsets.clone(),
+        }
+    }
+
+    /// Create a parcel from a snapshot taken by `capture`
+    pub fn from_capture(captured: &CapturedParcel) -> Self {
+        Self::from_parts(captured.data.clone(), captured.offsets.clone())
+    }
+
+    /// Create a parcel from a hex dump of its data, such as the output of `to_hex`. Whitespace,
+    /// commas and `0x` prefixes are ignored, so dumps can be pasted as they are. The parcel has no
+    /// object offsets.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let digits = hex
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .map(|word| word.trim_start_matches("0x").trim_start_matches("0X"))
+            .collect::<String>();
+        if digits.len() % 2 != 0 || !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+            return Err(Error::DeserializationError);
+        }
+        let data = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+            .collect();
+        Ok(Self::from_parts(data, vec![]))
+    }
+
+    /// Dump the parcel's data as hex, one space separated group of 4 bytes per `u32`
+    pub fn to_hex(&self) -> String {
+        self.to_slice()
+            .chunks(4)
+            .map(|word| word.iter().map(|byte| format!("{:02x}", byte)).collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Consume the parcel, returning its data without copying it
+    pub fn into_vec(self) -> Vec<u8> {
+        self.cursor.into_inner()
+    }
+
+    /// Consume the parcel, returning its data and object offsets without copying them
+    pub fn into_parts(self) -> (Vec<u8>, Vec<usize>) {
+        (self.cursor.into_inner(), self.object_offsets)
+    }
+
+    /// Compute a hash of the parcel's data, suitable for deduplicating or caching requests. This
+    /// uses 64-bit FNV-1a, so the value is stable across runs and builds.
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        self.to_slice().iter().fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(FNV_PRIME)
+        })
+    }
+
+    /// Retrieve the size of the parcel's data. This is everything written so far, wherever the
+    /// cursor is, see `bytes_read` and `bytes_remaining` for the cursor's progress through it.
     pub fn len(&self) -> usize {
         self.cursor.get_ref().len()
     }
@@ -119,55 +362,194 @@ impl Parcel {
     }
 
     /// Retrieve the object offsets
-    pub fn offsets(&mut self) -> &mut Vec<usize> {
+    pub fn offsets(&self) -> &[usize] {
+        &self.object_offsets
+    }
+
+    /// Retrieve the object offsets for modification
+    pub fn offsets_mut(&mut self) -> &mut Vec<usize> {
         &mut self.object_offsets
     }
 
     pub fn push_object(&mut self) -> Result<(), Error> {
+        self.align_for_object()?;
         self.object_offsets.push(self.cursor.position() as usize);
         Ok(())
     }
 
+    /// Pad the data with zeroes so that the next object is written at an offset the driver accepts,
+    /// in case a preceding write (such as `write_u8`) left the cursor unaligned.
+    fn align_for_object(&mut self) -> Result<(), Error> {
+        let misalignment = self.cursor.position() % OBJECT_ALIGNMENT;
+        if misalignment != 0 {
+            let padding = [0u8; OBJECT_ALIGNMENT as usize];
+            self.cursor.write_all(&padding[..(OBJECT_ALIGNMENT - misalignment) as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Skip the padding added by `align_for_object`, if the next object follows it.
+    fn skip_object_padding(&mut self) {
+        let position = self.cursor.position();
+        let aligned = (position + OBJECT_ALIGNMENT - 1) & !(OBJECT_ALIGNMENT - 1);
+        if aligned != position && self.next_object_offset() == Some(aligned as usize) {
+            self.cursor.set_position(aligned);
+        }
+    }
+
+    /// The read-side counterpart of `push_object`: check that the cursor is at the next recorded
+    /// object offset and move on to the following one, so that objects are consumed in order as they
+    /// are read. Anywhere else is an `Error::NotAnObject`, as the reader has lost track of the data.
+    pub fn pop_object(&mut self) -> Result<(), Error> {
+        self.check_object_boundary()?;
+        self.objects_position += 1;
+        Ok(())
+    }
+
+    /// Check that the object offsets are increasing, aligned, and each point at a whole object with
+    /// a valid type within the data, so that a bad parcel is caught before the driver rejects it.
+    pub fn validate_offsets(&self) -> Result<(), Error> {
+        let mut end_of_previous = 0;
+        for &offset in &self.object_offsets {
+            if offset < end_of_previous
+                || offset as u64 & (OBJECT_ALIGNMENT - 1) != 0
+                || offset + FLAT_OBJECT_SIZE > self.len()
+                || BinderType::try_from(self.read_u32_at(offset as u64)?).is_err()
+            {
+                return Err(Error::CorruptOffsets(offset));
+            }
+            end_of_previous = offset + FLAT_OBJECT_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Make the next object to be read the first one at or after the cursor, after the cursor has
+    /// been moved other than by reading objects, for instance past data which was skipped.
+    fn seek_objects(&mut self) {
+        let position = self.cursor.position() as usize;
+        self.objects_position = self.object_offsets.partition_point(|&offset| offset < position);
+    }
+
+    /// Retrieve the offset of the next object to be read, if any remain
+    pub fn next_object_offset(&self) -> Option<usize> {
+        self.object_offsets.get(self.objects_position).copied()
+    }
+
+    /// Check that the cursor is at the next object to be read, so that arbitrary data is not
+    /// interpreted as a binder or file descriptor object
+    fn check_object_boundary(&mut self) -> Result<(), Error> {
+        self.skip_object_padding();
+        let position = self.cursor.position();
+        if self.next_object_offset() != Some(position as usize) {
+            return Err(Error::NotAnObject { position });
+        }
+        Ok(())
+    }
+
     /// Check if the parcel has unread data
     pub fn has_unread_data(&self) -> bool {
-        self.cursor.position() != self.len() as u64
+        self.bytes_remaining() != 0
+    }
+
+    /// The number of bytes before the cursor, that is those already read (or written, when
+    /// building a parcel)
+    pub fn bytes_read(&self) -> usize {
+        (self.cursor.position() as usize).min(self.len())
+    }
+
+    /// The number of bytes between the cursor and the end of the parcel's data
+    pub fn bytes_remaining(&self) -> usize {
+        self.len().saturating_sub(self.cursor.position() as usize)
     }
 
     /// Write an i32 to the parcel
     pub fn write_i32(&mut self, data: i32) -> Result<(), Error> {
+        parcel_trace!("write_i32({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_i32::<LittleEndian>(data)?;
         Ok(())
     }
     /// Write an u32 to the parcel
     pub fn write_u32(&mut self, data: u32) -> Result<(), Error> {
+        parcel_trace!("write_u32({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_u32::<LittleEndian>(data)?;
         Ok(())
     }
     /// Write an u64 to the parcel
+    ///
+    /// Like Android's `Parcel::writeUint64`, this only relies on the parcel's 4-byte alignment and
+    /// adds no padding, so an u64 following an odd number of i32s starts at an offset of 4 mod 8.
     pub fn write_u64(&mut self, data: u64) -> Result<(), Error> {
+        parcel_trace!("write_u64({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_u64::<LittleEndian>(data)?;
         Ok(())
     }
     /// Write an u16 to the parcel
     pub fn write_u16(&mut self, data: u16) -> Result<(), Error> {
+        parcel_trace!("write_u16({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_u16::<LittleEndian>(data)?;
         Ok(())
     }
+    /// Write a big endian u16 to the parcel, for payloads with their own byte order. Everything
+    /// Android writes is little endian, use `write_u16` for that.
+    pub fn write_u16_be(&mut self, data: u16) -> Result<(), Error> {
+        parcel_trace!("write_u16_be({}) @ offset {}", data, self.cursor.position());
+        self.cursor.write_u16::<BigEndian>(data)?;
+        Ok(())
+    }
+    /// Write a big endian u32 to the parcel, for payloads with their own byte order. Everything
+    /// Android writes is little endian, use `write_u32` for that.
+    pub fn write_u32_be(&mut self, data: u32) -> Result<(), Error> {
+        parcel_trace!("write_u32_be({}) @ offset {}", data, self.cursor.position());
+        self.cursor.write_u32::<BigEndian>(data)?;
+        Ok(())
+    }
+    /// Write a big endian u64 to the parcel, for payloads with their own byte order. Everything
+    /// Android writes is little endian, use `write_u64` for that.
+    pub fn write_u64_be(&mut self, data: u64) -> Result<(), Error> {
+        parcel_trace!("write_u64_be({}) @ offset {}", data, self.cursor.position());
+        self.cursor.write_u64::<BigEndian>(data)?;
+        Ok(())
+    }
+
+    /// Write a char to the parcel as a single UTF-16 code unit, padded to 4 bytes. Characters
+    /// outside the basic multilingual plane do not fit in one code unit and are rejected.
+    pub fn write_char16(&mut self, data: char) -> Result<(), Error> {
+        parcel_trace!("write_char16({:?}) @ offset {}", data, self.cursor.position());
+        let mut units = [0u16; 2];
+        match data.encode_utf16(&mut units) {
+            [unit] => self.write_u32(*unit as u32),
+            _ => Err(Error::InvalidChar(data as u32)),
+        }
+    }
 
     /// Write a bool to the parcel
     pub fn write_bool(&mut self, data: bool) -> Result<(), Error> {
+        parcel_trace!("write_bool({}) @ offset {}", data, self.cursor.position());
         self.write_u32(data as u32)?;
         Ok(())
     }
 
     /// Write an u8 to the parcel
     pub fn write_u8(&mut self, data: u8) -> Result<(), Error>{
+        parcel_trace!("write_u8({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_u8(data as u8)?;
         Ok(())
     }
 
+    /// Write a C-style enum to the parcel as its `i32` discriminant
+    pub fn write_enum<E: Into<i32> + Copy>(&mut self, value: E) -> Result<(), Error> {
+        self.write_i32(value.into())
+    }
+
+    /// Read a C-style enum from its `i32` discriminant, failing with `BadEnumValue` if the value
+    /// does not correspond to a variant
+    pub fn read_enum<E: TryFrom<i32>>(&mut self) -> Result<E, Error> {
+        E::try_from(self.read_i32()?).map_err(|_| Error::BadEnumValue)
+    }
+
     /// Write an usize to the parcel
     pub fn write_usize(&mut self, data: usize) -> Result<(), Error> {
+        parcel_trace!("write_usize({}) @ offset {}", data, self.cursor.position());
         self.cursor.write_u64::<LittleEndian>(data as u64)?;
         Ok(())
     }
@@ -175,6 +557,7 @@ impl Parcel {
 
     /// Write a slice of data to the parcel
     pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        parcel_trace!("write({:?}) @ offset {}", data, self.cursor.position());
         let padded_len = (data.len() + 3) & !3;
 
         let mut data = data.to_vec();
@@ -189,34 +572,79 @@ impl Parcel {
     }
 
     /// Write a BinderTransactionData struct into the parcel
+    ///
+    /// Each field is written explicitly in the kernel's `binder_transaction_data` layout, so the
+    /// result does not depend on the host's struct layout or pointer width.
     pub fn write_transaction_data(&mut self, data: &BinderTransactionData) -> Result<(), Error>{
-        self.write(unsafe {
-            slice::from_raw_parts(
-                data as *const _ as *const u8,
-                size_of::<BinderTransactionData>(),
-            )
-        })?;
-        Ok(())
+        parcel_trace!("write_transaction_data({:?}) @ offset {}", data, self.cursor.position());
+        data.serialize(self)
     }
 
     /// Read an u8 from the parcel
     pub fn read_u8(&mut self) -> Result<u8, Error> {
-        Ok(self.cursor.read_u8()?)
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u8()?;
+        parcel_trace!("read_u8 -> {} @ offset {}", value, offset);
+        Ok(value)
     }
 
     /// Read an u16 from the parcel
     pub fn read_u16(&mut self) -> Result<u16, Error> {
-        Ok(self.cursor.read_u16::<LittleEndian>()?)
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u16::<LittleEndian>()?;
+        parcel_trace!("read_u16 -> {} @ offset {}", value, offset);
+        Ok(value)
+    }
+
+    /// Read a char written as a single UTF-16 code unit from the parcel
+    pub fn read_char16(&mut self) -> Result<char, Error> {
+        let offset = self.cursor.position();
+        let unit = self.cursor.read_u32::<LittleEndian>()?;
+        let value = std::char::from_u32(unit)
+            .filter(|_| unit <= u16::MAX as u32)
+            .ok_or(Error::InvalidChar(unit))?;
+        parcel_trace!("read_char16 -> {:?} @ offset {}", value, offset);
+        Ok(value)
     }
 
     /// Read an u32 from the parcel
     pub fn read_u32(&mut self) -> Result<u32, Error> {
-        Ok(self.cursor.read_u32::<LittleEndian>()?)
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u32::<LittleEndian>()?;
+        parcel_trace!("read_u32 -> {} @ offset {}", value, offset);
+        Ok(value)
     }
 
-    /// Read an u64 from the parcel
+    /// Read an u64 from the parcel, which is 4-byte aligned like everything else (see `write_u64`)
     pub fn read_u64(&mut self) -> Result<u64, Error> {
-        Ok(self.cursor.read_u64::<LittleEndian>()?)
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u64::<LittleEndian>()?;
+        parcel_trace!("read_u64 -> {} @ offset {}", value, offset);
+        Ok(value)
+    }
+
+    /// Read a big endian u16 from the parcel, the counterpart of `write_u16_be`
+    pub fn read_u16_be(&mut self) -> Result<u16, Error> {
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u16::<BigEndian>()?;
+        parcel_trace!("read_u16_be -> {} @ offset {}", value, offset);
+        Ok(value)
+    }
+
+    /// Read a big endian u32 from the parcel, the counterpart of `write_u32_be`
+    pub fn read_u32_be(&mut self) -> Result<u32, Error> {
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u32::<BigEndian>()?;
+        parcel_trace!("read_u32_be -> {} @ offset {}", value, offset);
+        Ok(value)
+    }
+
+    /// Read a big endian u64 from the parcel, the counterpart of `write_u64_be`
+    pub fn read_u64_be(&mut self) -> Result<u64, Error> {
+        let offset = self.cursor.position();
+        let value = self.cursor.read_u64::<BigEndian>()?;
+        parcel_trace!("read_u64_be -> {} @ offset {}", value, offset);
+        Ok(value)
     }
 
     /// Read an usize from the parcel
@@ -230,7 +658,10 @@ impl Parcel {
 
     /// Read an i32 from the parcel
     pub fn read_i32(&mut self) -> Result<i32, Error> {
-        Ok(self.cursor.read_i32::<LittleEndian>()?)
+        let offset = self.cursor.position();
+        let value = self.cursor.read_i32::<LittleEndian>()?;
+        parcel_trace!("read_i32 -> {} @ offset {}", value, offset);
+        Ok(value)
     }
 
     /// Read a void pointer from the parcel
@@ -245,25 +676,77 @@ impl Parcel {
         } else {
             size
         };
+        let offset = self.cursor.position();
         let mut data = vec![0u8; size];
         self.cursor.read(&mut data)?;
+        parcel_trace!("read({}) -> {:?} @ offset {}", size, data, offset);
         Ok(data)
     }
 
     /// Read a slice of size bytes from the parcel
     pub fn read_without_alignment(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let offset = self.cursor.position();
         let mut data = vec![0u8; size];
         self.cursor.read(&mut data)?;
+        parcel_trace!("read_without_alignment({}) -> {:?} @ offset {}", size, data, offset);
         Ok(data)
     }
 
+    /// Advance the cursor past `size` bytes, rounded up to a multiple of 4 as `read` does, without
+    /// copying them out
+    pub fn skip(&mut self, size: usize) -> Result<(), Error> {
+        self.skip_without_alignment((size + 3) & !3)
+    }
+
+    /// Advance the cursor past exactly `size` bytes, without copying them out
+    pub fn skip_without_alignment(&mut self, size: usize) -> Result<(), Error> {
+        parcel_trace!("skip({}) @ offset {}", size, self.cursor.position());
+        if size > self.bytes_remaining() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        self.cursor.set_position(self.cursor.position() + size as u64);
+        self.seek_objects();
+        Ok(())
+    }
+
+    /// Trace a field of `T` read or written from `start` up to the cursor. This is what
+    /// `#[parcelable(trace)]` generates for each field, and, like all parcel tracing, only emits
+    /// anything when the `parcel-trace` feature is enabled.
+    #[cfg(feature = "parcel-trace")]
+    pub fn trace_field<T: ?Sized>(&self, field: &str, start: u64, value: &dyn fmt::Debug) {
+        parcel_trace!(
+            "{}.{} = {:?} @ offset {}..{}",
+            std::any::type_name::<T>(),
+            field,
+            value,
+            start,
+            self.cursor.position()
+        );
+    }
+
+    /// Without the `parcel-trace` feature, the calls `#[parcelable(trace)]` generates compile to
+    /// nothing.
+    #[cfg(not(feature = "parcel-trace"))]
+    #[inline(always)]
+    pub fn trace_field<T: ?Sized>(&self, _field: &str, _start: u64, _value: &dyn fmt::Debug) {}
+
     /// Read a BinderTransactionData from the parcel
     pub fn read_transaction_data(&mut self) -> Result<BinderTransactionData, Error> {
-        Ok(self.read_object()?)
+        BinderTransactionData::deserialize(self)
     }
 
-    /// Read an object of type T from the parcel
+    /// Read an object of type T, written with `write_object`, from the parcel. The cursor must be
+    /// at the next object offset, or an `Error::NotAnObject` is returned.
     pub fn read_object<T>(&mut self) -> Result<T, Error> {
+        parcel_trace!(
+            "read_object<{}> @ offset {}",
+            std::any::type_name::<T>(),
+            self.cursor.position()
+        );
+        self.pop_object()?;
+        if size_of::<T>() > self.bytes_remaining() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
         unsafe {
             let data = slice::from_raw_parts(
                 self.cursor
@@ -278,17 +761,56 @@ impl Parcel {
     }
 
     pub fn write_object<T>(&mut self, object: T) -> Result<(), Error>{
-        self.object_offsets.push(self.cursor.position() as usize);
+        parcel_trace!(
+            "write_object<{}> @ offset {}",
+            std::any::type_name::<T>(),
+            self.cursor.position()
+        );
+        self.push_object()?;
         self.cursor.write(unsafe {
             slice::from_raw_parts(&object as *const _ as *const u8, size_of::<T>())
         })?;
         Ok(())
     }
 
-    /// Write a string to the parcel
+    /// Write a parcelable as an object the driver translates: its offset is recorded at the
+    /// current, aligned, position and it is then serialized with its own layout, rather than copied
+    /// raw as `write_object` does. Types which record their offset themselves, deriving
+    /// `Parcelable` with `push_object` like `BinderFlatObject`, are only recorded once.
+    pub fn write_parcelable_object<T: Parcelable>(&mut self, object: &T) -> Result<(), Error> {
+        parcel_trace!("write_parcelable_object({:?}) @ offset {}", object, self.cursor.position());
+        self.align_for_object()?;
+        let position = self.cursor.position() as usize;
+        let objects = self.object_offsets.len();
+        object.serialize(self)?;
+        if self.object_offsets.get(objects) != Some(&position) {
+            self.object_offsets.insert(objects, position);
+        }
+        Ok(())
+    }
+
+    /// Read a parcelable written by `write_parcelable_object`. The cursor must be at the next object
+    /// offset, or an `Error::NotAnObject` is returned.
+    pub fn read_parcelable_object<T: Parcelable>(&mut self) -> Result<T, Error> {
+        let offset = self.cursor.position();
+        self.check_object_boundary()?;
+        let objects_position = self.objects_position;
+        let object = T::deserialize(self)?;
+        if self.objects_position == objects_position {
+            self.objects_position += 1;
+        }
+        parcel_trace!("read_parcelable_object -> {:?} @ offset {}", object, offset);
+        Ok(object)
+    }
+
+    /// Write a string to the parcel as UTF-16, like Java's `Parcel.writeString` and C++'s
+    /// `writeString16`
     pub fn write_str16(&mut self, string: &str) -> Result<(), Error> {
+        parcel_trace!("write_str16({:?}) @ offset {}", string, self.cursor.position());
         let mut s16: Vec<u8> = vec![];
-        self.write_i32(string.len() as i32)?;
+        // The length is counted in UTF-16 code units, which is not the UTF-8 byte length
+        let len = string.encode_utf16().count();
+        self.write_i32(string_length(len)?)?;
         for c in string.encode_utf16() {
             s16.write_u16::<LittleEndian>(c)?;
         }
@@ -303,13 +825,15 @@ impl Parcel {
         Ok(())
     }
 
-    /// Write a string to the parcel
+    /// Write a string to the parcel as UTF-8, like C++'s `writeCString`/`writeString8`. This is not
+    /// what Java's `writeString` writes, see `write_string`.
     pub fn write_str(&mut self, string: &str) -> Result<(), Error>{
-        let mut s8: Vec<u8> = Vec::with_capacity(string.len() + 1);
-        self.write_i32(string.len() as i32)?;
-        for c in string.bytes() {
-            s8.push(c);
-        }
+        parcel_trace!("write_str({:?}) @ offset {}", string, self.cursor.position());
+        let mut s8: Vec<u8> = Vec::with_capacity(string.len() + 4);
+        // `len` is the byte length of the UTF-8 encoding, which is what `read_str` expects
+        let len = string_length(string.len())?;
+        self.write_i32(len)?;
+        s8.extend_from_slice(string.as_bytes());
         s8.push(0);
 
         if s8.len() % 4 != 0 {
@@ -321,69 +845,662 @@ impl Parcel {
         Ok(())
     }
 
+    /// Read the noted app-ops header which may start a reply to a call made with
+    /// `TransactionFlags::CollectNotedAppOps`, returning the attribution tag and the bitmask of
+    /// noted ops for each attribution. The bitmask is written with `writeLongArray`, so a null one
+    /// reads as empty. If there is no such header, nothing is read, and the list is empty.
+    pub fn read_noted_app_ops(&mut self) -> Result<Vec<(String, Vec<u64>)>, Error> {
+        let start = self.cursor.position();
+        if self.bytes_remaining() < size_of::<i32>() || self.read_i32()? != EX_HAS_NOTED_APPOPS_REPLY_HEADER {
+            self.cursor.set_position(start);
+            return Ok(vec![]);
+        }
+        let count = self.read_i32()?;
+        if count < 0 {
+            return Err(Error::InvalidLength(count));
+        }
+        let noted_app_ops = (0..count)
+            .map(|_| Ok((self.read_str16()?, self.read_vec_nullable()?.unwrap_or_default())))
+            .collect::<Result<Vec<_>, Error>>()?;
+        parcel_trace!("read_noted_app_ops -> {:?} @ offset {}", noted_app_ops, start);
+        Ok(noted_app_ops)
+    }
+
+    /// Write the status of a successful call, as Android's `writeNoException`, which precedes the
+    /// results in a reply
+    pub fn write_no_exception(&mut self) -> Result<(), Error> {
+        self.write_i32(0)
+    }
+
+    /// Write the status of a failed call, as Android's `writeException`: the exception code (such as
+    /// `-3` for `IllegalArgumentException`), its message, and an empty remote stack trace.
+    pub fn write_exception(&mut self, code: i32, message: &str) -> Result<(), Error> {
+        parcel_trace!("write_exception({}, {:?}) @ offset {}", code, message, self.cursor.position());
+        self.write_i32(code)?;
+        self.write_str16(message)?;
+        self.write_i32(0)
+    }
+
+    /// Write a nullable parcelable to the parcel, matching Android's `writeTypedObject`: a present
+    /// flag (`1` or `0`) followed by the object itself.
+    pub fn write_typed_object<T: Parcelable>(&mut self, object: Option<&T>) -> Result<(), Error> {
+        parcel_trace!("write_typed_object({:?}) @ offset {}", object, self.cursor.position());
+        if let Some(object) = object {
+            self.write_i32(1)?;
+            object.serialize(self)?;
+        } else {
+            self.write_i32(0)?;
+        }
+        Ok(())
+    }
+
+    /// Read a nullable parcelable written by `write_typed_object` (or Android's
+    /// `writeTypedObject`) from the parcel.
+    pub fn read_typed_object<T: Parcelable>(&mut self) -> Result<Option<T>, Error> {
+        let offset = self.cursor.position();
+        let object = if self.read_i32()? != 0 {
+            Some(self.read_nested(T::deserialize)?)
+        } else {
+            None
+        };
+        parcel_trace!("read_typed_object -> {:?} @ offset {}", object, offset);
+        Ok(object)
+    }
+
+    /// Write a parcelable preceded by its Java class name, as Android's `writeParcelable` does.
+    /// Unlike the size prefixed envelope of stable AIDL parcelables, the class name is all that
+    /// comes before the body.
+    pub fn write_java_parcelable<T: Parcelable>(&mut self, class_name: &str, object: &T) -> Result<(), Error> {
+        parcel_trace!("write_java_parcelable({:?}, {:?}) @ offset {}", class_name, object, self.cursor.position());
+        self.write_str16(class_name)?;
+        object.serialize(self)
+    }
+
+    /// Read a parcelable written by `write_java_parcelable` (or Android's `writeParcelable`) whose
+    /// type is known, returning its class name along with it. A null parcelable, which has a null
+    /// class name and no body, is a `DeserializationError`.
+    pub fn read_java_parcelable<T: Parcelable>(&mut self) -> Result<(String, T), Error> {
+        let offset = self.cursor.position();
+        let class_name = self.read_str16_nullable()?.ok_or(Error::DeserializationError)?;
+        let object = self.read_nested(T::deserialize)?;
+        parcel_trace!("read_java_parcelable -> {:?}, {:?} @ offset {}", class_name, object, offset);
+        Ok((class_name, object))
+    }
+
+    /// Write a parcelable preceded by its Java class name, so that the receiver can tell which type
+    /// to decode. This is the same as `write_java_parcelable`.
+    pub fn write_polymorphic<T: Parcelable>(&mut self, class_name: &str, object: &T) -> Result<(), Error> {
+        self.write_java_parcelable(class_name, object)
+    }
+
+    /// Read a parcelable written by `write_polymorphic` (or Android's `writeParcelable`), decoding
+    /// it with the type `registry` has for its class name. The result can be downcast to that
+    /// type. An unregistered class name is an `UnknownParcelableClass` error.
+    pub fn read_polymorphic(&mut self, registry: &ParcelableRegistry) -> Result<Box<dyn Any>, Error> {
+        let offset = self.cursor.position();
+        let class_name = self.read_str16()?;
+        parcel_trace!("read_polymorphic -> {:?} @ offset {}", class_name, offset);
+        self.read_nested(|parcel| registry.create(&class_name, parcel))
+    }
+
+    /// Write a placeholder u32 to the parcel, whose value can be filled in later using `patch_u32`.
+    /// This is useful for length fields which are only known after the data they describe has been
+    /// written.
+    pub fn write_placeholder_u32(&mut self) -> Result<PlaceholderToken, Error> {
+        let position = self.cursor.position();
+        self.write_u32(0)?;
+        Ok(PlaceholderToken { position })
+    }
+
+    /// Fill in a placeholder written by `write_placeholder_u32`, leaving the cursor where it was.
+    pub fn patch_u32(&mut self, token: PlaceholderToken, value: u32) -> Result<(), Error> {
+        self.write_u32_at(token.position, value)
+    }
+
+    /// Overwrite the u32 at `pos` with `value`, without moving the cursor. The u32 must lie
+    /// within the data already written to the parcel.
+    pub fn write_u32_at(&mut self, pos: u64, value: u32) -> Result<(), Error> {
+        let data = self
+            .cursor
+            .get_mut()
+            .get_mut(pos as usize..pos as usize + size_of::<u32>())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        LittleEndian::write_u32(data, value);
+        Ok(())
+    }
+
+    /// Read the u32 at `pos`, without moving the cursor.
+    pub fn read_u32_at(&self, pos: u64) -> Result<u32, Error> {
+        let data = self
+            .cursor
+            .get_ref()
+            .get(pos as usize..pos as usize + size_of::<u32>())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        Ok(LittleEndian::read_u32(data))
+    }
+
+    /// Write a stable parcelable envelope, as generated by AIDL for structured parcelables: an `i32`
+    /// holding the total size of the envelope (including the size itself), followed by the body
+    /// written by `write_body`.
+    pub fn write_parcelable_envelope<F>(&mut self, write_body: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Self) -> Result<(), Error>,
+    {
+        let start = self.cursor.position();
+        let size = self.write_placeholder_u32()?;
+        write_body(self)?;
+        let end = self.cursor.position();
+        self.patch_u32(size, (end - start) as u32)
+    }
+
+    /// Read a stable parcelable envelope, using `read_body` to read its contents. Any trailing
+    /// bytes which `read_body` did not consume (such as fields added by a newer version of the
+    /// parcelable) are skipped, so the cursor always ends up at the field following the envelope.
+    /// If `read_body` reads past the end of the envelope, the parcel has desynced and an
+    /// `InvalidLength` error is returned.
+    pub fn read_parcelable_envelope<T, F>(&mut self, read_body: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<T, Error>,
+    {
+        let start = self.cursor.position();
+        let size = self.read_i32()?;
+        if size < size_of::<i32>() as i32 || size as usize - size_of::<i32>() > self.bytes_remaining() {
+            return Err(Error::InvalidLength(size));
+        }
+        let end = start + size as u64;
+        let body = self.read_nested(read_body)?;
+        if self.cursor.position() > end {
+            return Err(Error::InvalidLength(size));
+        }
+        self.cursor.set_position(end);
+        self.seek_objects();
+        Ok(body)
+    }
+
     /// Write a Binder object into the parcel
     pub fn write_binder(&mut self, object: *const c_void) -> Result<(), Error> {
-        BinderFlatObject::new(BinderType::Binder, object as usize, 0, 0).serialize(self)?;
+        self.write_binder_with_cookie(object, 0)
+    }
+
+    /// Write a Binder object into the parcel, along with a cookie identifying it. The driver hands
+    /// the cookie back with every transaction sent to the object, as `BinderTransactionData::cookie`.
+    pub fn write_binder_with_cookie(&mut self, object: *const c_void, cookie: usize) -> Result<(), Error> {
+        parcel_trace!("write_binder({:?}, {:#x}) @ offset {}", object, cookie, self.cursor.position());
+        BinderFlatObject::new(BinderType::Binder, object as usize, cookie, 0).serialize(self)?;
+        Ok(())
+    }
+
+    /// Write a weak reference to a local Binder object into the parcel. A weak reference does not
+    /// keep the object alive: the receiver only holds a weak count on it (managed with
+    /// `BC_INCREFS`/`BC_DECREFS`), and must promote it to a strong reference before use.
+    pub fn write_weak_binder(&mut self, object: *const c_void) -> Result<(), Error> {
+        parcel_trace!("write_weak_binder({:?}) @ offset {}", object, self.cursor.position());
+        BinderFlatObject::new(BinderType::WeakBinder, object as usize, 0, 0).serialize(self)?;
+        Ok(())
+    }
+
+    /// Write a weak reference to a remote Binder object, identified by its handle, into the
+    /// parcel. As with `write_weak_binder`, this only carries a weak count, so the caller should
+    /// hold its own weak reference (`Binder::add_ref`) on the handle for as long as it is in use.
+    pub fn write_weak_handle(&mut self, handle: i32) -> Result<(), Error> {
+        parcel_trace!("write_weak_handle({}) @ offset {}", handle, self.cursor.position());
+        BinderFlatObject::new(BinderType::WeakHandle, handle as usize, 0, 0).serialize(self)?;
         Ok(())
     }
 
-    /// Write a file descriptor into the parcel
+    /// Read a strong reference to a Binder object from the parcel. This is a `Handle` for a remote
+    /// object, or a `Binder` if the object lives in this process.
+    pub fn read_strong_binder(&mut self) -> Result<BinderFlatObject, Error> {
+        let offset = self.cursor.position();
+        self.check_object_boundary()?;
+        let flat_object = BinderFlatObject::deserialize(self)?;
+        if flat_object.binder_type != BinderType::Binder
+            && flat_object.binder_type != BinderType::Handle
+        {
+            return Err(Error::UnexpectedObjectType {
+                expected: BinderType::Handle,
+                found: flat_object.binder_type,
+            });
+        }
+        parcel_trace!("read_strong_binder -> {:?} @ offset {}", flat_object, offset);
+        Ok(flat_object)
+    }
+
+    /// Read a strong reference which may be null, as written by Android's `writeStrongBinder` for a
+    /// `@nullable` binder. A null binder is a local binder with a null pointer, which has no object
+    /// offset, and is returned as `None`.
+    pub fn read_nullable_strong_binder(&mut self) -> Result<Option<BinderFlatObject>, Error> {
+        self.skip_object_padding();
+        let position = self.cursor.position();
+        if self.next_object_offset() == Some(position as usize) {
+            return self.read_strong_binder().map(Some);
+        }
+        let binder_type = BinderType::deserialize(self)?;
+        let _flags = self.read_u32()?;
+        let binder = self.read_usize()?;
+        let _cookie = self.read_usize()?;
+        let _stability = self.read_u32()?;
+        if binder_type != BinderType::Binder || binder != 0 {
+            return Err(Error::NotAnObject { position });
+        }
+        parcel_trace!("read_nullable_strong_binder -> None @ offset {}", position);
+        Ok(None)
+    }
+
+    /// Read a weak reference to a Binder object from the parcel. This is a `WeakHandle` for a
+    /// remote object, or a `WeakBinder` if the object lives in this process.
+    pub fn read_weak_binder(&mut self) -> Result<BinderFlatObject, Error> {
+        let offset = self.cursor.position();
+        self.check_object_boundary()?;
+        let flat_object = BinderFlatObject::deserialize(self)?;
+        if flat_object.binder_type != BinderType::WeakBinder
+            && flat_object.binder_type != BinderType::WeakHandle
+        {
+            return Err(Error::UnexpectedObjectType {
+                expected: BinderType::WeakHandle,
+                found: flat_object.binder_type,
+            });
+        }
+        parcel_trace!("read_weak_binder -> {:?} @ offset {}", flat_object, offset);
+        Ok(flat_object)
+    }
+
+    /// Write a file descriptor into the parcel, with the same object flags as Android's
+    /// `writeFileDescriptor`. If `take_ownership` is set, the parcel on the receiving side owns, and
+    /// eventually closes, its copy of the fd.
     pub fn write_file_descriptor(&mut self, fd: RawFd, take_ownership: bool) -> Result<(), Error>{
-        BinderFlatObject::new(BinderType::Fd, fd as usize, if take_ownership { 1 } else { 0 }, 0x17f).serialize(self)?;
+        self.write_file_descriptor_with_flags(fd, take_ownership, FD_OBJECT_FLAGS)
+    }
+
+    /// Write a file descriptor into the parcel with the given `FLAT_BINDER_FLAG_*` object flags
+    pub fn write_file_descriptor_with_flags(
+        &mut self,
+        fd: RawFd,
+        take_ownership: bool,
+        flags: u32,
+    ) -> Result<(), Error> {
+        parcel_trace!(
+            "write_file_descriptor({}, {}, {:#x}) @ offset {}",
+            fd,
+            take_ownership,
+            flags,
+            self.cursor.position()
+        );
+        let cookie = if take_ownership { 1 } else { 0 };
+        BinderFd::new(BinderType::Fd, fd as usize, cookie, flags).serialize(self)?;
         Ok(())
     }
 
-    /// REad a file descriptor from the parcel
+    /// Write a blob of data to the parcel, matching Android's `writeBlob`. Blobs larger than
+    /// `BLOB_INPLACE_LIMIT` are placed in a shared memory region (a `memfd`) and only its file
+    /// descriptor is written, avoiding the binder transaction size limit.
+    ///
+    /// `Parcel` does not track file descriptors, so when shared memory is used the file owning its
+    /// fd is returned, and must be kept open until the transaction has been sent. The driver gives
+    /// the receiver its own fd, which `read_blob` closes, so a parcel read back in this process
+    /// consumes the fd instead: use `into_raw_fd` rather than dropping the file then.
+    pub fn write_blob(&mut self, data: &[u8]) -> Result<Option<File>, Error> {
+        parcel_trace!("write_blob({} bytes) @ offset {}", data.len(), self.cursor.position());
+        self.write_i32(blob_length(data.len())?)?;
+        if data.len() <= BLOB_INPLACE_LIMIT {
+            self.write_i32(BLOB_INPLACE)?;
+            self.write(data)?;
+            return Ok(None);
+        }
+
+        let name = CStr::from_bytes_with_nul(b"Parcel Blob\0").unwrap();
+        let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC)?;
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(data)?;
+
+        self.write_i32(BLOB_ASHMEM_IMMUTABLE)?;
+        // The returned file owns the fd, not the parcel
+        self.write_file_descriptor(file.as_raw_fd(), false)?;
+        Ok(Some(file))
+    }
+
+    /// Read the file descriptor passed as the first argument of a `DUMP_TRANSACTION`. By
+    /// convention, the dump output is written to this fd rather than into the reply parcel. It is
+    /// followed by an `i32` argument count and that many `str16` arguments.
+    pub fn read_dump_fd(&mut self) -> Result<RawFd, Error> {
+        self.read_file_descriptor()
+    }
+
+    /// Read a blob of data written by `write_blob` (or Android's `writeBlob`) from the parcel. If
+    /// the data was passed through shared memory, the received file descriptor is closed once the
+    /// data has been copied out. A length longer than the data in the parcel, or in the shared
+    /// memory, is an `InvalidLength` error.
+    pub fn read_blob(&mut self) -> Result<Vec<u8>, Error> {
+        let offset = self.cursor.position();
+        let raw_len = self.read_i32()?;
+        if raw_len < 0 {
+            return Err(Error::InvalidLength(raw_len));
+        }
+        let len = raw_len as usize;
+        let data = match self.read_i32()? {
+            BLOB_INPLACE => {
+                if len > self.bytes_remaining() {
+                    return Err(Error::InvalidLength(raw_len));
+                }
+                let mut data = self.read(len)?;
+                data.truncate(len);
+                data
+            }
+            BLOB_ASHMEM_IMMUTABLE | BLOB_ASHMEM_MUTABLE => {
+                let file = unsafe { File::from_raw_fd(self.read_file_descriptor()?) };
+                if len as u64 > file.metadata()?.len() {
+                    return Err(Error::InvalidLength(raw_len));
+                }
+                let mut data = vec![0u8; len];
+                file.read_exact_at(&mut data, 0)?;
+                data
+            }
+            _ => return Err(Error::BadEnumValue),
+        };
+        parcel_trace!("read_blob -> {} bytes @ offset {}", data.len(), offset);
+        Ok(data)
+    }
+
+    /// Read a file descriptor from the parcel. Any other kind of object is an
+    /// `UnexpectedObjectType` error.
+    ///
+    /// The driver translates fds into the receiving process. A parcel which never went through the
+    /// driver, such as one written and read back in the same process, returns the fd that was
+    /// written, so an fd from `memfd_create` can stand in for a real one when testing.
     pub fn read_file_descriptor(&mut self) -> Result<RawFd, Error> {
-        let flat_object: BinderFlatObject = self.read_object()?;
-        assert!(flat_object.binder_type == BinderType::Fd);
-        Ok(flat_object.handle as RawFd)
+        let offset = self.cursor.position();
+        self.check_object_boundary()?;
+        let fd_object = BinderFd::deserialize(self)?;
+        if fd_object.binder_type != BinderType::Fd {
+            return Err(Error::UnexpectedObjectType {
+                expected: BinderType::Fd,
+                found: fd_object.binder_type,
+            });
+        }
+        parcel_trace!("read_file_descriptor -> {} @ offset {}", fd_object.handle, offset);
+        Ok(fd_object.handle as RawFd)
     }
 
-    /// Read a string from the parcel
+    /// Read a UTF-16 string from the parcel, as written by Java's `Parcel.writeString`
     pub fn read_str16(&mut self) -> Result<String, Error> {
-        let len = (self.read_i32()? + 1) as usize;
-        if len == 0 {
+        let offset = self.cursor.position();
+        let raw_len = self.read_i32()?;
+        if raw_len == -1 {
             return Ok("".to_string())
         }
-        unsafe {
-            let u16_array: Vec<u16> = self.read(len * 2)?.chunks_exact(2).into_iter().map(|a| u16::from_ne_bytes([a[0], a[1]])).collect();
-            let mut res = String::from_utf16(&u16_array)?;
-            res.truncate(len - 1);
-            Ok(res)
+        if raw_len < -1 {
+            return Err(Error::InvalidLength(raw_len));
+        }
+        // The code units, plus the null terminator, are padded to a multiple of 4 bytes
+        let size = (raw_len as usize + 1) * 2;
+        if size > self.bytes_remaining() {
+            return Err(Error::InvalidLength(raw_len));
+        }
+        // Decode straight from the buffer, reading the code units as little endian whatever the
+        // host's byte order is.
+        let start = self.cursor.position() as usize;
+        // Like Android, insist on the terminator, which catches a length that does not match the
+        // data that follows it.
+        if self.cursor.get_ref()[start + size - 2..start + size] != [0, 0] {
+            return Err(Error::InvalidLength(raw_len));
         }
+        let units = self.cursor.get_ref()[start..start + size - 2]
+            .chunks_exact(2)
+            .map(|a| u16::from_le_bytes([a[0], a[1]]));
+        let res = std::char::decode_utf16(units).collect::<Result<String, _>>()?;
+        self.cursor.set_position(((start + size + 3) & !3) as u64);
+        parcel_trace!("read_str16 -> {:?} @ offset {}", res, offset);
+        Ok(res)
     }
 
-    /// Read a string from the parcel
+    /// Read a UTF-8 string from the parcel, as written by C++'s `writeString8`. This is not what
+    /// Java's `writeString` writes, see `read_string`.
     pub fn read_str(&mut self) -> Result<String, Error> {
-        let len = (self.read_i32()? + 1) as usize;
-        if len == 0 {
+        let offset = self.cursor.position();
+        let raw_len = self.read_i32()?;
+        if raw_len == -1 {
             return Ok("".to_string())
         }
-        unsafe {
-            let u8_array = self.read(len)?;
-            let mut res = String::from_utf8(u8_array)?;
-            res.truncate(len - 1);
-            Ok(res)
+        // The length is the UTF-8 byte count, not including the null terminator, and the whole
+        // thing is padded to a multiple of 4 bytes.
+        if raw_len < -1 || raw_len as usize + 1 > self.bytes_remaining() {
+            return Err(Error::InvalidLength(raw_len));
+        }
+        let len = raw_len as usize;
+        let mut u8_array = self.read(len + 1)?;
+        if u8_array[len] != 0 {
+            return Err(Error::DeserializationError);
+        }
+        u8_array.truncate(len);
+        let res = String::from_utf8(u8_array)?;
+        parcel_trace!("read_str -> {:?} @ offset {}", res, offset);
+        Ok(res)
+    }
+
+    /// Write a string as Java's `Parcel.writeString` does, which is the same as `write_str16`
+    pub fn write_string(&mut self, string: &str) -> Result<(), Error> {
+        self.write_str16(string)
+    }
+
+    /// Read a string written by Java's `Parcel.writeString`, which is the same as `read_str16`
+    pub fn read_string(&mut self) -> Result<String, Error> {
+        self.read_str16()
+    }
+
+    /// Write a nullable string to the parcel, using a length of `-1` for `None`
+    pub fn write_str16_nullable(&mut self, string: Option<&str>) -> Result<(), Error> {
+        match string {
+            Some(string) => self.write_str16(string),
+            None => self.write_i32(-1),
+        }
+    }
+
+    /// Read a nullable string from the parcel, where a length of `-1` means `None`
+    pub fn read_str16_nullable(&mut self) -> Result<Option<String>, Error> {
+        let start = self.cursor.position();
+        if self.read_i32()? == -1 {
+            return Ok(None);
+        }
+        self.cursor.set_position(start);
+        Ok(Some(self.read_str16()?))
+    }
+
+    /// Write a nullable UTF-8 string to the parcel, using a length of `-1` for `None`, unlike
+    /// `write_str` which can only write a present string
+    pub fn write_str_nullable(&mut self, string: Option<&str>) -> Result<(), Error> {
+        match string {
+            Some(string) => self.write_str(string),
+            None => self.write_i32(-1),
+        }
+    }
+
+    /// Read a nullable UTF-8 string from the parcel, where a length of `-1` means `None`. `read_str`
+    /// reads that as an empty string instead.
+    pub fn read_str_nullable(&mut self) -> Result<Option<String>, Error> {
+        let start = self.cursor.position();
+        if self.read_i32()? == -1 {
+            return Ok(None);
+        }
+        self.cursor.set_position(start);
+        Ok(Some(self.read_str()?))
+    }
+
+    /// Write a nullable array to the parcel, using a length of `-1` for `None`
+    pub fn write_vec_nullable<T: Parcelable>(&mut self, vec: Option<&Vec<T>>) -> Result<(), Error> {
+        match vec {
+            Some(vec) => vec.serialize(self),
+            None => self.write_i32(-1),
+        }
+    }
+
+    /// Read a nullable array from the parcel, where a length of `-1` means `None`
+    pub fn read_vec_nullable<T: Parcelable>(&mut self) -> Result<Option<Vec<T>>, Error> {
+        let start = self.cursor.position();
+        if self.read_i32()? == -1 {
+            return Ok(None);
+        }
+        self.cursor.set_position(start);
+        Ok(Some(Vec::deserialize(self)?))
+    }
+
+    /// Read a list written as an `i32` count followed by its elements, yielding the elements
+    /// lazily from the cursor instead of collecting them into a `Vec`. Elements which are not
+    /// consumed are left unread, so the cursor ends up after the last element taken.
+    pub fn read_list_iter<T: Parcelable>(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<T, Error>> + '_, Error> {
+        let len = self.read_i32()?;
+        if len < -1 {
+            return Err(Error::InvalidLength(len));
+        }
+        parcel_trace!("read_list_iter<{}>({})", std::any::type_name::<T>(), len);
+        Ok((0..len.max(0)).map(move |_| T::deserialize(self)))
+    }
+
+    /// Write a list of boxed values, as Android's `writeList`: the count, then each element
+    /// preceded by its `writeValue` type tag.
+    fn write_boxed_list<T: Parcelable>(&mut self, values: &[T], tag: i32) -> Result<(), Error> {
+        self.write_i32(values.len() as i32)?;
+        for value in values {
+            self.write_i32(tag)?;
+            value.serialize(self)?;
+        }
+        Ok(())
+    }
+
+    /// Read a list written by `write_boxed_list`, or Android's `writeList`, whose elements must all
+    /// be tagged with `tag`. A null list is read as an empty one, while null elements are rejected.
+    fn read_boxed_list<T: Parcelable>(&mut self, tag: i32) -> Result<Vec<T>, Error> {
+        let len = self.read_i32()?;
+        if len == VAL_NULL {
+            return Ok(vec![]);
+        }
+        if len < 0 || len as usize > self.bytes_remaining() / size_of::<i32>() {
+            return Err(Error::InvalidLength(len));
+        }
+        (0..len)
+            .map(|_| {
+                if self.read_i32()? != tag {
+                    return Err(Error::BadEnumValue);
+                }
+                T::deserialize(self)
+            })
+            .collect()
+    }
+
+    /// Write a list of ints boxed as a Java `List<Integer>`. Use this when the other side expects a
+    /// `List`, and a `Vec<i32>`, which is written as a primitive `int[]`, when it expects an array.
+    pub fn write_boxed_int_list(&mut self, values: &[i32]) -> Result<(), Error> {
+        self.write_boxed_list(values, VAL_INTEGER)
+    }
+
+    /// Read a Java `List<Integer>` from the parcel
+    pub fn read_boxed_int_list(&mut self) -> Result<Vec<i32>, Error> {
+        self.read_boxed_list(VAL_INTEGER)
+    }
+
+    /// Write a list of longs boxed as a Java `List<Long>`
+    pub fn write_boxed_long_list(&mut self, values: &[i64]) -> Result<(), Error> {
+        self.write_boxed_list(values, VAL_LONG)
+    }
+
+    /// Read a Java `List<Long>` from the parcel
+    pub fn read_boxed_long_list(&mut self) -> Result<Vec<i64>, Error> {
+        self.read_boxed_list(VAL_LONG)
+    }
+
+    /// Write a list of bools boxed as a Java `List<Boolean>`
+    pub fn write_boxed_bool_list(&mut self, values: &[bool]) -> Result<(), Error> {
+        self.write_boxed_list(values, VAL_BOOLEAN)
+    }
+
+    /// Read a Java `List<Boolean>` from the parcel
+    pub fn read_boxed_bool_list(&mut self) -> Result<Vec<bool>, Error> {
+        self.read_boxed_list(VAL_BOOLEAN)
+    }
+
+    /// Write a value as Android's `writeValue` does: its `VAL_*` type tag followed by the value.
+    pub fn write_value(&mut self, value: &Value) -> Result<(), Error> {
+        parcel_trace!("write_value({:?}) @ offset {}", value, self.cursor.position());
+        match value {
+            Value::Null => self.write_i32(VAL_NULL),
+            Value::String(string) => {
+                self.write_i32(VAL_STRING)?;
+                self.write_str16(string)
+            }
+            Value::Int(int) => {
+                self.write_i32(VAL_INTEGER)?;
+                self.write_i32(*int)
+            }
+            Value::Long(long) => {
+                self.write_i32(VAL_LONG)?;
+                self.write_u64(*long as u64)
+            }
+            Value::Double(double) => {
+                self.write_i32(VAL_DOUBLE)?;
+                self.write_u64(double.to_bits())
+            }
+            Value::Bool(bool) => {
+                self.write_i32(VAL_BOOLEAN)?;
+                self.write_i32(*bool as i32)
+            }
+            Value::ByteArray(data) => {
+                self.write_i32(VAL_BYTEARRAY)?;
+                self.write_i32(data.len() as i32)?;
+                self.write(data)
+            }
         }
     }
 
+    /// Read a value written by Android's `writeValue`: a `VAL_*` type tag followed by the value.
+    /// Tags other than those of the scalar types in `Value` give `Error::UnsupportedValueTag`.
+    pub fn read_value(&mut self) -> Result<Value, Error> {
+        let offset = self.cursor.position();
+        let value = match self.read_i32()? {
+            VAL_NULL => Value::Null,
+            VAL_STRING => Value::String(self.read_str16()?),
+            VAL_INTEGER => Value::Int(self.read_i32()?),
+            VAL_LONG => Value::Long(self.read_u64()? as i64),
+            VAL_DOUBLE => Value::Double(f64::from_bits(self.read_u64()?)),
+            VAL_BOOLEAN => Value::Bool(self.read_i32()? != 0),
+            VAL_BYTEARRAY => {
+                let len = self.read_i32()?;
+                if len < -1 || len > self.bytes_remaining() as i32 {
+                    return Err(Error::InvalidLength(len));
+                }
+                let len = len.max(0) as usize;
+                let mut data = self.read(len)?;
+                data.truncate(len);
+                Value::ByteArray(data)
+            }
+            tag => return Err(Error::UnsupportedValueTag(tag)),
+        };
+        parcel_trace!("read_value -> {:?} @ offset {}", value, offset);
+        Ok(value)
+    }
+
     /// Read an interface token from the parcel
     pub fn read_interface_token(&mut self) -> Result<String, Error> {
+        parcel_trace!("read_interface_token @ offset {}", self.cursor.position());
         //assert!(self.read_i32() == STRICT_MODE_PENALTY_GATHER);
         self.read_i32()?;
-        assert!(self.read_i32()? == -1);
-        assert!(self.read_i32()? == HEADER);
-        Ok(self.read_str16()?)
+        // The work source is whatever the caller set with `Binder::set_work_source_uid`
+        self.read_i32()?;
+        // A malformed token means the rest of the parcel can't be trusted either, so this is an
+        // error rather than a panic.
+        if self.read_i32()? != HEADER {
+            return Err(Error::DeserializationError);
+        }
+        self.read_str16()
     }
 
-
     /// Write an interface token to the parcel
     pub fn write_interface_token(&mut self, name: &str) -> Result<(), Error>{
+        parcel_trace!("write_interface_token({:?}) @ offset {}", name, self.cursor.position());
         // strict mode policy
         self.write_i32(STRICT_MODE_PENALTY_GATHER | 0x42000004)?;
-        // work source uid, we use kUnsetWorkSource
-        self.write_i32(-1)?;
+        // work source uid, as set for this thread with `Binder::set_work_source_uid`
+        self.write_i32(Binder::work_source_uid())?;
         // header marker
         self.write_i32(HEADER)?;
         // the interface name
@@ -392,3 +1509,906 @@ impl Parcel {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn skipping_past_an_object_reads_the_next_one() {
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        let object_size = parcel.len();
+        parcel.write_i32(7).unwrap();
+        parcel.write_binder(2 as *const c_void).unwrap();
+        parcel.set_position(0);
+
+        parcel.skip(object_size).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 7);
+        assert_eq!(parcel.read_strong_binder().unwrap().handle, 2);
+    }
+
+    #[test]
+    fn envelope_resync_skips_unread_objects() {
+        let mut parcel = Parcel::empty();
+        parcel
+            .write_parcelable_envelope(|parcel| {
+                parcel.write_i32(1)?;
+                parcel.write_binder(3 as *const c_void)
+            })
+            .unwrap();
+        parcel.write_binder(2 as *const c_void).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(parcel.read_parcelable_envelope(|parcel| parcel.read_i32()).unwrap(), 1);
+        assert_eq!(parcel.read_strong_binder().unwrap().handle, 2);
+    }
+
+    #[test]
+    fn set_position_rewinds_objects() {
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(parcel.read_strong_binder().unwrap().handle, 3);
+        parcel.set_position(0);
+        assert_eq!(parcel.read_strong_binder().unwrap().handle, 3);
+    }
+
+    #[test]
+    fn parcelable_object_is_recorded_once() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(1).unwrap();
+        parcel
+            .write_parcelable_object(&BinderFlatObject::new(BinderType::Binder, 2, 0, 0))
+            .unwrap();
+        parcel.write_parcelable_object(&7i32).unwrap();
+        assert_eq!(parcel.offsets().len(), 2);
+        parcel.set_position(0);
+
+        assert_eq!(parcel.read_i32().unwrap(), 1);
+        assert_eq!(parcel.read_parcelable_object::<BinderFlatObject>().unwrap().handle, 2);
+        assert_eq!(parcel.read_parcelable_object::<i32>().unwrap(), 7);
+    }
+
+    #[test]
+    fn noted_app_ops_header() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(EX_HAS_NOTED_APPOPS_REPLY_HEADER).unwrap();
+        parcel.write_i32(2).unwrap();
+        parcel.write_str16("tag").unwrap();
+        parcel.write_i32(2).unwrap();
+        parcel.write_u64(1 << 40).unwrap();
+        parcel.write_u64(3).unwrap();
+        parcel.write_str16("other").unwrap();
+        parcel.write_i32(-1).unwrap();
+        parcel.write_no_exception().unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(
+            parcel.read_noted_app_ops().unwrap(),
+            vec![("tag".to_string(), vec![1 << 40, 3]), ("other".to_string(), vec![])]
+        );
+        assert_eq!(parcel.read_i32().unwrap(), 0);
+        assert!(parcel.read_noted_app_ops().unwrap().is_empty());
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        for len in [5, BLOB_INPLACE_LIMIT + 1] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let mut parcel = Parcel::empty();
+            let file = parcel.write_blob(&data).unwrap();
+            assert_eq!(file.is_some(), len > BLOB_INPLACE_LIMIT);
+            parcel.write_i32(9).unwrap();
+            parcel.set_position(0);
+
+            assert_eq!(parcel.read_blob().unwrap(), data);
+            assert_eq!(parcel.read_i32().unwrap(), 9);
+            // Reading the blob back closed the shared memory's fd
+            if let Some(file) = file {
+                let fd = file.into_raw_fd();
+                assert_eq!(nix::unistd::close(fd), Err(nix::Error::Sys(nix::errno::Errno::EBADF)));
+            }
+        }
+    }
+
+    #[test]
+    fn blob_lengths_are_checked() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(-2).unwrap();
+        parcel.write_i32(BLOB_INPLACE).unwrap();
+        parcel.set_position(0);
+        assert!(matches!(parcel.read_blob(), Err(Error::InvalidLength(-2))));
+
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(16).unwrap();
+        parcel.write_i32(BLOB_INPLACE).unwrap();
+        parcel.write(&[0; 8]).unwrap();
+        parcel.set_position(0);
+        assert!(matches!(parcel.read_blob(), Err(Error::InvalidLength(16))));
+
+        let name = CStr::from_bytes_with_nul(b"blob\0").unwrap();
+        let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(&[0; 8]).unwrap();
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(16).unwrap();
+        parcel.write_i32(BLOB_ASHMEM_IMMUTABLE).unwrap();
+        // read_blob closes the fd
+        parcel.write_file_descriptor(file.into_raw_fd(), true).unwrap();
+        parcel.set_position(0);
+        assert!(matches!(parcel.read_blob(), Err(Error::InvalidLength(16))));
+    }
+
+    #[test]
+    fn append_parcel_sorts_offsets() {
+        let mut other = Parcel::empty();
+        other.write_binder(3 as *const c_void).unwrap();
+        let object_size = other.len();
+
+        // Leave room for the other parcel in front of this one's object
+        let mut parcel = Parcel::empty();
+        parcel.write(&vec![0; object_size]).unwrap();
+        parcel.write_binder(2 as *const c_void).unwrap();
+        let object = parcel.offsets()[0];
+        parcel.set_position(0);
+        parcel.append_parcel(&mut other).unwrap();
+        assert_eq!(parcel.offsets(), &[0, object]);
+    }
+
+    #[test]
+    fn append_parcel_rejects_overlapping_objects() {
+        let mut other = Parcel::empty();
+        other.write_binder(3 as *const c_void).unwrap();
+
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(2 as *const c_void).unwrap();
+        for position in [0, 8] {
+            parcel.set_position(position);
+            assert!(matches!(
+                parcel.append_parcel(&mut other),
+                Err(Error::CorruptOffsets(offset)) if offset == position as usize
+            ));
+            assert_eq!(parcel.offsets(), &[0]);
+        }
+    }
+
+    #[cfg(feature = "parcel-trace")]
+    #[test]
+    fn trace_logs_each_read_and_write() {
+        use crate::testing::capture_trace;
+
+        let mut parcel = Parcel::empty();
+        let written = capture_trace(|| {
+            parcel.write_i32(5).unwrap();
+            parcel.write_str16("foo").unwrap();
+        });
+        parcel.set_position(0);
+        let read = capture_trace(|| {
+            parcel.read_i32().unwrap();
+            parcel.read_str16().unwrap();
+        });
+        // Strings trace their length too, as they write and read it
+        assert_eq!(written, ["write_i32(5) @ offset 0", "write_str16(\"foo\") @ offset 4", "write_i32(3) @ offset 4"]);
+        assert_eq!(read, ["read_i32 -> 5 @ offset 0", "read_i32 -> 3 @ offset 4", "read_str16 -> \"foo\" @ offset 4"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn capture_round_trips_through_json() {
+        let mut parcel = Parcel::empty();
+        parcel.write_str16("com.example.ITest").unwrap();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        parcel.write_i32(7).unwrap();
+
+        let json = serde_json::to_string(&parcel.capture()).unwrap();
+        let captured: CapturedParcel = serde_json::from_str(&json).unwrap();
+        assert_eq!(captured, parcel.capture());
+        let mut replayed = Parcel::from_capture(&captured);
+        assert_eq!(replayed.to_slice(), parcel.to_slice());
+        assert_eq!(replayed.offsets(), &[parcel.offsets()[0]]);
+        assert_eq!(replayed.read_str16().unwrap(), "com.example.ITest");
+        assert_eq!(replayed.read_strong_binder().unwrap().handle, 3);
+        assert_eq!(replayed.read_i32().unwrap(), 7);
+    }
+
+    #[test]
+    fn string_lengths_are_checked() {
+        // -5, i32::MAX, and one past what the parcel holds
+        for len in [-5, i32::MAX, 4] {
+            let mut parcel = Parcel::empty();
+            parcel.write_i32(len).unwrap();
+            parcel.write_i32(0).unwrap();
+            parcel.set_position(0);
+            assert!(matches!(parcel.read_str(), Err(Error::InvalidLength(l)) if l == len));
+            parcel.set_position(0);
+            assert!(matches!(parcel.read_str16(), Err(Error::InvalidLength(l)) if l == len));
+        }
+    }
+
+    #[test]
+    fn placeholder_is_patched_in_place() {
+        let mut parcel = Parcel::empty();
+        let token = parcel.write_placeholder_u32().unwrap();
+        parcel.write(&[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        parcel.patch_u32(token, 8).unwrap();
+        parcel.write_i32(-1).unwrap();
+
+        assert_eq!(parcel.to_slice(), [8, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn u32_at_leaves_the_cursor_alone() {
+        let mut parcel = Parcel::empty();
+        parcel.write_u32(0xdead_beef).unwrap();
+        parcel.write_u32(2).unwrap();
+        parcel.write_u32_at(0, 1).unwrap();
+        assert_eq!(parcel.read_u32_at(4).unwrap(), 2);
+        parcel.write_u32(3).unwrap();
+        assert_eq!(parcel.to_slice(), [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0]);
+
+        // Past the data written so far
+        assert!(parcel.write_u32_at(10, 0).is_err());
+        assert!(parcel.read_u32_at(12).is_err());
+    }
+
+    #[test]
+    fn equal_contents_hash_equal() {
+        use std::collections::hash_map::DefaultHasher;
+
+        let hash = |parcel: &Parcel| {
+            let mut hasher = DefaultHasher::new();
+            parcel.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut first = Parcel::empty();
+        first.write_str16("request").unwrap();
+        first.write_i32(1).unwrap();
+        let mut second = Parcel::from_slice(first.to_slice());
+        // Wherever the cursor is
+        second.set_position(4);
+        assert_eq!(first, second);
+        assert_eq!(first.content_hash(), second.content_hash());
+        assert_eq!(hash(&first), hash(&second));
+
+        second.write_u32_at(second.len() as u64 - 4, 2).unwrap();
+        assert_ne!(first, second);
+        assert_ne!(first.content_hash(), second.content_hash());
+        assert_ne!(hash(&first), hash(&second));
+    }
+
+    #[test]
+    fn envelope_skips_what_the_body_does_not_read() {
+        let mut parcel = Parcel::empty();
+        parcel
+            .write_parcelable_envelope(|parcel| {
+                parcel.write_i32(1)?;
+                parcel.write_u64(2)?;
+                parcel.write_str16("newer")
+            })
+            .unwrap();
+        parcel.write_i32(9).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(parcel.read_parcelable_envelope(|parcel| parcel.read_i32()).unwrap(), 1);
+        assert_eq!(parcel.read_i32().unwrap(), 9);
+    }
+
+    #[test]
+    fn received_object_is_forwarded_as_an_object() {
+        let mut sent = Parcel::empty();
+        sent.write_i32(1).unwrap();
+        sent.write_binder(3 as *const c_void).unwrap();
+        let mut received = Parcel::from_parts(sent.to_slice().to_vec(), sent.offsets().to_vec());
+
+        let mut forwarded = Parcel::empty();
+        forwarded.write_i32(received.read_i32().unwrap()).unwrap();
+        assert_eq!(received.next_object_offset(), Some(4));
+        received.read_strong_binder().unwrap().serialize(&mut forwarded).unwrap();
+        assert_eq!(received.next_object_offset(), None);
+
+        assert_eq!(forwarded, sent);
+        assert_eq!(forwarded.offsets(), &[4]);
+    }
+
+    #[repr(i32)]
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum State {
+        Idle = 0,
+        Running = 3,
+    }
+
+    impl num_traits::FromPrimitive for State {
+        fn from_i64(value: i64) -> Option<Self> {
+            match value {
+                0 => Some(State::Idle),
+                3 => Some(State::Running),
+                _ => None,
+            }
+        }
+
+        fn from_u64(value: u64) -> Option<Self> {
+            Self::from_i64(value as i64)
+        }
+    }
+
+    impl From<State> for i32 {
+        fn from(state: State) -> i32 {
+            state as i32
+        }
+    }
+
+    impl TryFrom<i32> for State {
+        type Error = ();
+
+        fn try_from(value: i32) -> Result<Self, ()> {
+            num_traits::FromPrimitive::from_i32(value).ok_or(())
+        }
+    }
+
+    #[test]
+    fn enum_round_trip() {
+        let mut parcel = Parcel::empty();
+        parcel.write_enum(State::Running).unwrap();
+        parcel.write_enum(State::Idle).unwrap();
+        parcel.write_i32(2).unwrap();
+        parcel.set_position(0);
+        assert_eq!(parcel.read_enum::<State>().unwrap(), State::Running);
+        assert_eq!(parcel.read_enum::<State>().unwrap(), State::Idle);
+        assert!(matches!(parcel.read_enum::<State>(), Err(Error::BadEnumValue)));
+    }
+
+    #[test]
+    fn weak_object_layout() {
+        let mut parcel = Parcel::empty();
+        parcel.write_weak_binder(0x1234 as *const c_void).unwrap();
+        parcel.write_weak_handle(5).unwrap();
+
+        // type ('w', 'b'/'h', '*', BINDER_TYPE_LARGE), flags, binder or handle, cookie, stability
+        let mut expected = Parcel::empty();
+        for (binder_type, handle) in [(b'b', 0x1234), (b'h', 5)] {
+            expected.write_u32(u32::from_be_bytes([b'w', binder_type, b'*', 0x85])).unwrap();
+            expected.write_u32(0).unwrap();
+            expected.write_u64(handle).unwrap();
+            expected.write_u64(0).unwrap();
+            expected.write_u32(0xc).unwrap();
+        }
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+        assert_eq!(parcel.offsets(), &[0, 28]);
+
+        parcel.set_position(0);
+        let weak_binder = parcel.read_weak_binder().unwrap();
+        assert_eq!((weak_binder.binder_type, weak_binder.handle()), (BinderType::WeakBinder, 0x1234));
+        let weak_handle = parcel.read_weak_binder().unwrap();
+        assert_eq!((weak_handle.binder_type, weak_handle.handle()), (BinderType::WeakHandle, 5));
+    }
+
+    #[test]
+    fn offsets_through_a_shared_reference() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(1).unwrap();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        let offsets = |parcel: &Parcel| parcel.offsets().to_vec();
+        assert_eq!(offsets(&parcel), vec![4usize]);
+        parcel.offsets_mut().clear();
+        assert!(offsets(&parcel).is_empty());
+    }
+
+    #[test]
+    fn objects_are_read_in_order() {
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        parcel.write_file_descriptor(4, false).unwrap();
+        let fd_offset = parcel.offsets()[1];
+        parcel.set_position(0);
+
+        assert_eq!(parcel.next_object_offset(), Some(0));
+        assert_eq!(parcel.read_strong_binder().unwrap().handle(), 3);
+        assert_eq!(parcel.next_object_offset(), Some(fd_offset));
+        assert_eq!(parcel.read_file_descriptor().unwrap(), 4);
+        assert_eq!(parcel.next_object_offset(), None);
+
+        // Data which is not a recorded object is not read as one
+        let mut forged = Parcel::from_slice(parcel.to_slice());
+        assert!(matches!(forged.read_strong_binder(), Err(Error::NotAnObject { position: 0 })));
+    }
+
+    #[test]
+    fn clear_keeps_the_capacity() {
+        let mut parcel = Parcel::empty();
+        parcel.write(&[1; 4096]).unwrap();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        let capacity = parcel.capacity();
+        for _ in 0..100 {
+            parcel.clear();
+            assert!(parcel.is_empty());
+            assert!(parcel.offsets().is_empty());
+            parcel.write(&[2; 4096]).unwrap();
+            assert_eq!(parcel.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    fn utf8_string_round_trip() {
+        for string in ["", "abc", "abcd", "héllo", "日本語"] {
+            let mut parcel = Parcel::empty();
+            parcel.write_str(string).unwrap();
+            parcel.write_i32(7).unwrap();
+            // The byte length, then the bytes and a terminator, padded to 4 bytes
+            let padded = (string.len() + 1 + 3) & !3;
+            assert_eq!(parcel.len(), 4 + padded + 4, "{:?}", string);
+            assert_eq!(parcel.read_u32_at(0).unwrap() as usize, string.len());
+
+            parcel.set_position(0);
+            assert_eq!(parcel.read_str().unwrap(), string);
+            assert_eq!(parcel.read_i32().unwrap(), 7);
+        }
+    }
+
+    #[test]
+    fn utf16_string_matches_android() {
+        // As written by Android's `Parcel.writeString`: the length in code units, the code units
+        // and a null terminator, padded to 4 bytes
+        let cases: [(&str, &[u8]); 3] = [
+            ("hi", &[2, 0, 0, 0, b'h', 0, b'i', 0, 0, 0, 0, 0]),
+            ("é", &[1, 0, 0, 0, 0xe9, 0, 0, 0]),
+            ("😀", &[2, 0, 0, 0, 0x3d, 0xd8, 0x00, 0xde, 0, 0, 0, 0]),
+        ];
+        for (string, bytes) in cases {
+            let mut parcel = Parcel::empty();
+            parcel.write_str16(string).unwrap();
+            assert_eq!(parcel.to_slice(), bytes, "{:?}", string);
+            assert_eq!(Parcel::from_slice(bytes).read_str16().unwrap(), string);
+        }
+    }
+
+    #[test]
+    fn object_after_a_byte_is_aligned() {
+        let mut parcel = Parcel::empty();
+        parcel.write_u8(1).unwrap();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        parcel.write_u8(2).unwrap();
+        parcel.write_file_descriptor(4, false).unwrap();
+        for &offset in parcel.offsets() {
+            assert_eq!(offset as u64 % OBJECT_ALIGNMENT, 0, "{:?}", parcel.offsets());
+        }
+        parcel.validate_offsets().unwrap();
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_u8().unwrap(), 1);
+        assert_eq!(parcel.read_strong_binder().unwrap().handle(), 3);
+        assert_eq!(parcel.read_u8().unwrap(), 2);
+        assert_eq!(parcel.read_file_descriptor().unwrap(), 4);
+    }
+
+    #[test]
+    fn list_iter_reads_lazily() {
+        let mut parcel = Parcel::empty();
+        (0..100).collect::<Vec<i32>>().serialize(&mut parcel).unwrap();
+        parcel.set_position(0);
+
+        let first: Vec<i32> = parcel.read_list_iter().unwrap().take(3).collect::<Result<_, _>>().unwrap();
+        assert_eq!(first, [0, 1, 2]);
+        // Only the count and the elements taken have been read
+        assert_eq!(parcel.bytes_read(), 4 * 4);
+    }
+
+    #[test]
+    fn fd_object_layout() {
+        let mut parcel = Parcel::empty();
+        parcel.write_file_descriptor(5, false).unwrap();
+        parcel.write_file_descriptor(6, true).unwrap();
+        parcel.write_file_descriptor_with_flags(7, false, crate::FLAT_BINDER_FLAG_ACCEPTS_FDS).unwrap();
+
+        // type ('f', 'd', '*', BINDER_TYPE_LARGE), flags, fd, cookie (whether the receiver owns it)
+        let mut expected = Parcel::empty();
+        for (flags, fd, cookie) in [(0x17f, 5, 0), (0x17f, 6, 1), (0x100, 7, 0)] {
+            expected.write_u32(u32::from_be_bytes([b'f', b'd', b'*', 0x85])).unwrap();
+            expected.write_u32(flags).unwrap();
+            expected.write_u64(fd).unwrap();
+            expected.write_u64(cookie).unwrap();
+        }
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+        assert_eq!(parcel.offsets(), &[0, 24, 48]);
+    }
+
+    #[test]
+    fn boxed_list_round_trip() {
+        let mut parcel = Parcel::empty();
+        parcel.write_boxed_int_list(&[1, -2]).unwrap();
+        parcel.write_boxed_long_list(&[3]).unwrap();
+        parcel.write_boxed_bool_list(&[true, false]).unwrap();
+        // Unlike a primitive array, each element carries its type tag
+        assert_eq!(&parcel.to_slice()[..20], [2, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 254, 255, 255, 255]);
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_boxed_int_list().unwrap(), [1, -2]);
+        assert_eq!(parcel.read_boxed_long_list().unwrap(), [3]);
+        assert_eq!(parcel.read_boxed_bool_list().unwrap(), [true, false]);
+        assert!(!parcel.has_unread_data());
+
+        // A list of something else
+        parcel.set_position(0);
+        assert!(matches!(parcel.read_boxed_long_list(), Err(Error::BadEnumValue)));
+    }
+
+    #[test]
+    fn str16_decodes_little_endian_units() {
+        let string = "Grüße, 世界 🌍";
+        let units: Vec<u16> = string.encode_utf16().collect();
+        // Built by hand, so this holds whatever the host's byte order
+        let mut bytes = (units.len() as i32).to_le_bytes().to_vec();
+        bytes.extend(units.iter().chain(&[0]).flat_map(|unit| unit.to_le_bytes()));
+        bytes.resize((bytes.len() + 3) & !3, 0);
+        bytes.extend(7i32.to_le_bytes());
+
+        let mut parcel = Parcel::from_slice(&bytes);
+        assert_eq!(parcel.read_str16().unwrap(), string);
+        assert_eq!(parcel.read_i32().unwrap(), 7);
+    }
+
+    #[test]
+    fn strings_are_written_little_endian() {
+        let mut parcel = Parcel::empty();
+        parcel.write_str16("hé").unwrap();
+        parcel.write_char16('ß').unwrap();
+        parcel.write_str16_nullable(None).unwrap();
+        assert_eq!(
+            parcel.to_slice(),
+            [
+                2, 0, 0, 0, b'h', 0, 0xe9, 0, 0, 0, 0, 0,
+                0xdf, 0, 0, 0,
+                0xff, 0xff, 0xff, 0xff,
+            ]
+        );
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_str16().unwrap(), "hé");
+        assert_eq!(parcel.read_char16().unwrap(), 'ß');
+        assert_eq!(parcel.read_str16_nullable().unwrap(), None);
+    }
+
+    #[test]
+    fn read_value_reads_each_tag() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(VAL_NULL).unwrap();
+        parcel.write_i32(VAL_STRING).unwrap();
+        parcel.write_str16("extra").unwrap();
+        parcel.write_i32(VAL_INTEGER).unwrap();
+        parcel.write_i32(-7).unwrap();
+        parcel.write_i32(VAL_LONG).unwrap();
+        parcel.write_u64(1 << 40).unwrap();
+        parcel.write_i32(VAL_DOUBLE).unwrap();
+        parcel.write_u64(2.5f64.to_bits()).unwrap();
+        parcel.write_i32(VAL_BOOLEAN).unwrap();
+        parcel.write_i32(1).unwrap();
+        parcel.write_i32(VAL_BYTEARRAY).unwrap();
+        parcel.write_i32(3).unwrap();
+        parcel.write(&[1, 2, 3]).unwrap();
+        // VAL_MAP
+        parcel.write_i32(2).unwrap();
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_value().unwrap(), Value::Null);
+        assert_eq!(parcel.read_value().unwrap(), Value::String("extra".to_string()));
+        assert_eq!(parcel.read_value().unwrap(), Value::Int(-7));
+        assert_eq!(parcel.read_value().unwrap(), Value::Long(1 << 40));
+        assert_eq!(parcel.read_value().unwrap(), Value::Double(2.5));
+        assert_eq!(parcel.read_value().unwrap(), Value::Bool(true));
+        assert_eq!(parcel.read_value().unwrap(), Value::ByteArray(vec![1, 2, 3]));
+        assert!(matches!(parcel.read_value(), Err(Error::UnsupportedValueTag(2))));
+    }
+
+    #[test]
+    fn value_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::String("Grüße".to_string()),
+            Value::Int(i32::MIN),
+            Value::Long(-1),
+            Value::Double(-0.125),
+            Value::Bool(false),
+            Value::ByteArray(vec![9; 5]),
+            Value::ByteArray(vec![]),
+        ];
+        let mut parcel = Parcel::empty();
+        for value in &values {
+            parcel.write_value(value).unwrap();
+        }
+
+        parcel.set_position(0);
+        for value in &values {
+            assert_eq!(&parcel.read_value().unwrap(), value);
+        }
+        assert!(!parcel.has_unread_data());
+    }
+
+    #[test]
+    fn parts_round_trip() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(1).unwrap();
+        parcel.write_file_descriptor(5, false).unwrap();
+        parcel.write_str16("after").unwrap();
+        let (data, offsets) = parcel.clone().into_parts();
+        assert_eq!(data, parcel.to_slice());
+        assert_eq!(offsets, vec![4usize]);
+
+        let mut rebuilt = Parcel::from_parts(data, offsets);
+        assert_eq!(rebuilt, parcel);
+        assert_eq!(rebuilt.read_i32().unwrap(), 1);
+        assert_eq!(rebuilt.read_file_descriptor().unwrap(), 5);
+        assert_eq!(rebuilt.read_str16().unwrap(), "after");
+        assert_eq!(rebuilt.into_vec(), parcel.to_slice());
+    }
+
+    #[test]
+    fn skip_over_a_string() {
+        let mut parcel = Parcel::empty();
+        parcel.write_str("skipped").unwrap();
+        parcel.write_i32(7).unwrap();
+        parcel.write_u8(1).unwrap();
+        parcel.write_i32(8).unwrap();
+
+        parcel.set_position(0);
+        let len = parcel.read_i32().unwrap() as usize;
+        // The terminator is part of the padded string
+        parcel.skip(len + 1).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 7);
+        // `write_u8` does not pad, so only its byte is skipped
+        parcel.skip_without_alignment(1).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 8);
+        assert!(parcel.skip(1).is_err());
+        assert!(!parcel.has_unread_data());
+    }
+
+    #[test]
+    fn write_string_is_java_write_string() {
+        // What Java's `Parcel.writeString("abc")` writes
+        let captured = [3, 0, 0, 0, b'a', 0, b'b', 0, b'c', 0, 0, 0];
+        let mut parcel = Parcel::empty();
+        parcel.write_string("abc").unwrap();
+        assert_eq!(parcel.to_slice(), captured);
+        assert_eq!(Parcel::from_slice(&captured).read_string().unwrap(), "abc");
+
+        // `write_str` is the C++ `writeCString`, which Java would misread
+        let mut parcel = Parcel::empty();
+        parcel.write_str("abc").unwrap();
+        assert_eq!(parcel.to_slice(), [3, 0, 0, 0, b'a', b'b', b'c', 0]);
+        assert_ne!(Parcel::from_slice(&captured).read_str().ok().as_deref(), Some("abc"));
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(-2).unwrap();
+        parcel.write_str16("hex").unwrap();
+        parcel.write_u8(0xab).unwrap();
+        assert_eq!(parcel.to_hex(), "feffffff 03000000 68006500 78000000 ab");
+        assert_eq!(Parcel::from_hex(&parcel.to_hex()).unwrap(), parcel);
+    }
+
+    #[test]
+    fn hex_dump_is_parsed() {
+        let mut parcel = Parcel::from_hex("0x01000000 0x0200\n  0x0000,0xFF").unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 1);
+        assert_eq!(parcel.read_i32().unwrap(), 2);
+        assert_eq!(parcel.bytes_remaining(), 1);
+
+        assert!(Parcel::from_hex("0x123").is_err());
+        assert!(Parcel::from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn object_of_the_wrong_type_is_an_error() {
+        let mut parcel = Parcel::empty();
+        parcel.write_binder(3 as *const c_void).unwrap();
+        parcel.write_file_descriptor(5, false).unwrap();
+
+        parcel.set_position(0);
+        assert!(matches!(
+            parcel.read_file_descriptor(),
+            Err(Error::UnexpectedObjectType { expected: BinderType::Fd, found: BinderType::Binder })
+        ));
+        parcel.set_position(0);
+        assert!(matches!(
+            parcel.read_weak_binder(),
+            Err(Error::UnexpectedObjectType { expected: BinderType::WeakHandle, found: BinderType::Binder })
+        ));
+        // The parcel is still usable after the error
+        assert_eq!(parcel.read_file_descriptor().unwrap(), 5);
+    }
+
+    #[test]
+    fn str16_lengths_one_to_four() {
+        for string in ["a", "ab", "abc", "abcd"] {
+            let mut parcel = Parcel::empty();
+            parcel.write_str16(string).unwrap();
+            parcel.write_i32(7).unwrap();
+            // The code units and the terminator, padded to 4 bytes
+            let padded = (2 * (string.len() + 1) + 3) & !3;
+            assert_eq!(parcel.len(), 4 + padded + 4, "{:?}", string);
+
+            parcel.set_position(0);
+            let read = parcel.read_str16().unwrap();
+            assert_eq!(read, string);
+            assert!(!read.contains('\0'));
+            assert_eq!(parcel.read_i32().unwrap(), 7);
+        }
+    }
+
+    #[test]
+    fn big_endian_round_trip() {
+        let mut parcel = Parcel::empty();
+        parcel.write_u16_be(0x0102).unwrap();
+        parcel.write_u32_be(0x0304_0506).unwrap();
+        parcel.write_u64_be(0x0708_090a_0b0c_0d0e).unwrap();
+        parcel.write_u32(0x0304_0506).unwrap();
+        assert_eq!(
+            parcel.to_slice(),
+            [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 6, 5, 4, 3]
+        );
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_u16_be().unwrap(), 0x0102);
+        assert_eq!(parcel.read_u32_be().unwrap(), 0x0304_0506);
+        assert_eq!(parcel.read_u64_be().unwrap(), 0x0708_090a_0b0c_0d0e);
+        // The default stays little-endian
+        assert_eq!(parcel.read_u32().unwrap(), 0x0304_0506);
+    }
+
+    #[test]
+    fn memfd_survives_the_mock_transport() {
+        let name = CStr::from_bytes_with_nul(b"fd\0").unwrap();
+        let fd = memfd_create(name, MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(b"through the fd").unwrap();
+        let mut reply = Parcel::empty();
+        reply.write_file_descriptor(file.into_raw_fd(), true).unwrap();
+
+        let (driver, mut binder) = crate::testing::MockDriver::new();
+        driver.queue_reply(&reply);
+        let (_, mut parcel) = binder.transact(1, 1, crate::TransactionFlags::empty(), &mut Parcel::empty()).unwrap();
+        let file = unsafe { File::from_raw_fd(parcel.read_file_descriptor().unwrap()) };
+        let mut data = [0; 14];
+        file.read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(&data, b"through the fd");
+    }
+
+    #[test]
+    fn len_is_independent_of_the_cursor() {
+        let mut parcel = Parcel::empty();
+        for value in 0..4 {
+            parcel.write_i32(value).unwrap();
+        }
+        assert_eq!((parcel.len(), parcel.bytes_read(), parcel.bytes_remaining()), (16, 16, 0));
+
+        parcel.set_position(0);
+        parcel.read_i32().unwrap();
+        assert_eq!((parcel.len(), parcel.bytes_read(), parcel.bytes_remaining()), (16, 4, 12));
+        assert!(parcel.has_unread_data());
+
+        // Appending after a partial read overwrites from the cursor on, growing the data as needed
+        let mut other = Parcel::empty();
+        for value in 10..15 {
+            other.write_i32(value).unwrap();
+        }
+        parcel.append_parcel(&mut other).unwrap();
+        assert_eq!((parcel.len(), parcel.bytes_read(), parcel.bytes_remaining()), (24, 24, 0));
+        parcel.set_position(0);
+        assert_eq!(parcel.read_i32().unwrap(), 0);
+        assert_eq!(parcel.read_i32().unwrap(), 10);
+        assert_eq!(parcel.bytes_remaining(), 16);
+
+        // A cursor past the end reads nothing and has nothing remaining
+        parcel.set_position(100);
+        assert_eq!((parcel.len(), parcel.bytes_read(), parcel.bytes_remaining()), (24, 24, 0));
+    }
+
+    #[test]
+    fn too_long_string_lengths_are_errors() {
+        // Strings this long can not be allocated in a test, so only their length is checked
+        assert_eq!(string_length(i32::MAX as usize).unwrap(), i32::MAX);
+        for len in [i32::MAX as usize + 1, usize::MAX] {
+            assert!(matches!(string_length(len), Err(Error::StringTooLong(l)) if l == len));
+        }
+    }
+
+    #[test]
+    fn too_long_blob_lengths_are_errors() {
+        assert_eq!(blob_length(i32::MAX as usize).unwrap(), i32::MAX);
+        for len in [i32::MAX as usize + 1, usize::MAX] {
+            assert!(matches!(blob_length(len), Err(Error::BlobTooLong(l)) if l == len));
+        }
+    }
+
+    #[test]
+    fn nullable_utf8_string_round_trip() {
+        let mut parcel = Parcel::empty();
+        for string in [None, Some(""), Some("x")] {
+            parcel.write_str_nullable(string).unwrap();
+        }
+        // Null is a length of -1, while an empty string still has its terminator
+        assert_eq!(
+            parcel.to_slice(),
+            [0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, b'x', 0, 0, 0]
+        );
+
+        parcel.set_position(0);
+        for string in [None, Some(""), Some("x")] {
+            assert_eq!(parcel.read_str_nullable().unwrap().as_deref(), string);
+        }
+    }
+
+    #[test]
+    fn interface_token_carries_the_work_source() {
+        let mut parcel = Parcel::empty();
+        {
+            let _guard = Binder::set_work_source_uid(1000);
+            parcel.write_interface_token("com.example.ITest").unwrap();
+        }
+        // The guard restored the work source
+        assert_eq!(Binder::work_source_uid(), crate::binder::UNSET_WORK_SOURCE);
+        let second = parcel.position();
+        parcel.write_interface_token("com.example.ITest").unwrap();
+
+        parcel.set_position(4);
+        assert_eq!(parcel.read_i32().unwrap(), 1000);
+        parcel.set_position(second + 4);
+        assert_eq!(parcel.read_i32().unwrap(), crate::binder::UNSET_WORK_SOURCE);
+        parcel.set_position(0);
+        assert_eq!(parcel.read_interface_token().unwrap(), "com.example.ITest");
+        assert_eq!(parcel.read_interface_token().unwrap(), "com.example.ITest");
+        assert!(!parcel.has_unread_data());
+    }
+
+    /// Strings of any characters, including none, those outside of the BMP and NULs, up to well
+    /// past the size of a page
+    fn any_string() -> impl Strategy<Value = String> {
+        prop::collection::vec(any::<char>(), 0..2000).prop_map(|chars| chars.into_iter().collect())
+    }
+
+    proptest! {
+        #[test]
+        fn interface_token_round_trip(name in any_string()) {
+            let mut parcel = Parcel::empty();
+            parcel.write_interface_token(&name).unwrap();
+            parcel.write_i32(7).unwrap();
+            parcel.set_position(0);
+            prop_assert_eq!(parcel.read_interface_token().unwrap(), name);
+            prop_assert_eq!(parcel.read_i32().unwrap(), 7);
+            prop_assert!(!parcel.has_unread_data());
+        }
+
+        #[test]
+        fn interface_token_round_trip_with_any_work_source(name in any_string(), uid in any::<i32>()) {
+            let mut parcel = Parcel::empty();
+            {
+                let _guard = Binder::set_work_source_uid(uid);
+                parcel.write_interface_token(&name).unwrap();
+            }
+            parcel.set_position(0);
+            prop_assert_eq!(parcel.read_interface_token().unwrap(), name);
+            prop_assert!(!parcel.has_unread_data());
+        }
+
+        #[test]
+        fn string_round_trip(first in any_string(), second in any_string()) {
+            let mut parcel = Parcel::empty();
+            parcel.write_str(&first).unwrap();
+            parcel.write_str16(&second).unwrap();
+            parcel.write_str16(&first).unwrap();
+            parcel.write_str(&second).unwrap();
+            parcel.write_i32(7).unwrap();
+            parcel.set_position(0);
+            prop_assert_eq!(parcel.read_str().unwrap(), first.clone());
+            prop_assert_eq!(parcel.read_str16().unwrap(), second.clone());
+            prop_assert_eq!(parcel.read_str16().unwrap(), first);
+            prop_assert_eq!(parcel.read_str().unwrap(), second);
+            prop_assert_eq!(parcel.read_i32().unwrap(), 7);
+            prop_assert!(!parcel.has_unread_data());
+        }
+    }
+}
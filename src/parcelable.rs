@@ -1,5 +1,15 @@
 use crate::{Error, Parcel};
-use std::{collections::HashMap, fmt::Debug, hash::Hash, str::FromStr};
+use std::{
+    any::Any,
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Debug},
+    hash::Hash,
+    net::{Ipv4Addr, Ipv6Addr},
+    num::{NonZeroI16, NonZeroI32, NonZeroI64, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU8},
+    os::unix::io::RawFd,
+    str::FromStr,
+};
 
 pub trait Parcelable: std::fmt::Debug {
     fn deserialize(parcel: &mut Parcel) -> Result<Self, Error>
@@ -8,6 +18,38 @@ pub trait Parcelable: std::fmt::Debug {
     fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error>;
 }
 
+/// The fields of a struct deriving `Parcelable`, without any envelope it is normally wrapped in.
+/// This is what `#[parcelable(flatten)]` uses to splice a struct's fields into its parent, so only
+/// such structs can be flattened:
+///
+/// ```compile_fail
+/// use binder_rust::{Error, Parcel, Parcelable, ParcelableFields};
+/// use parcelable_derive::Parcelable;
+///
+/// #[derive(Debug, Parcelable)]
+/// struct Parent {
+///     #[parcelable(flatten)]
+///     child: i32,
+/// }
+/// ```
+pub trait ParcelableFields: Parcelable {
+    /// Deserialize just the fields of this object
+    fn deserialize_fields(parcel: &mut Parcel) -> Result<Self, Error>
+    where
+        Self: Sized;
+
+    /// Serialize just the fields of this object, the counterpart of `deserialize_fields`
+    fn serialize_fields(&self, parcel: &mut Parcel) -> Result<(), Error>;
+}
+
+/// A parcelable which can be used behind a trait object, written after an `i32` tag identifying
+/// its concrete type. Fields of type `Box<dyn Trait>`, where `Trait: TaggedParcelable`, can be
+/// derived with `#[parcelable(tagged_trait = "create")]`, where `create` is a function
+/// `fn(i32, &mut Parcel) -> Result<Box<dyn Trait>, Error>` reading the concrete type for a tag.
+pub trait TaggedParcelable: Parcelable {
+    fn tag(&self) -> i32;
+}
+
 //impl Debug for dyn Parcelable {
 //fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 //write!(f, "{:?}", (*self).fmt(f))
@@ -29,6 +71,266 @@ impl Default for String16 {
     }
 }
 
+/// An `android.content.ComponentName`, identifying a component by its package and class name.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct ComponentName {
+    pub package: String,
+    pub class: String,
+}
+
+impl ComponentName {
+    pub fn new(package: &str, class: &str) -> Self {
+        Self {
+            package: package.to_string(),
+            class: class.to_string(),
+        }
+    }
+}
+
+/// Matches `ComponentName.writeToParcel`/`createFromParcel`. When used as a nullable field it should
+/// be written with `Parcel::write_typed_object`, which adds the present flag Android expects.
+impl Parcelable for ComponentName {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        Ok(Self {
+            package: parcel.read_str16()?,
+            class: parcel.read_str16()?,
+        })
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_str16(&self.package)?;
+        parcel.write_str16(&self.class)?;
+        Ok(())
+    }
+}
+
+/// A loosely typed value, as written by Android's `Parcel.writeValue` and read by `readValue`.
+/// Only the scalar types are supported.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Null,
+    String(String),
+    Int(i32),
+    Long(i64),
+    Double(f64),
+    Bool(bool),
+    ByteArray(Vec<u8>),
+}
+
+impl Parcelable for Value {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        parcel.read_value()
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_value(self)
+    }
+}
+
+/// A byte buffer, written as Android's `writeByteArray` does: the length, then the bytes packed
+/// together and padded to 4 bytes once at the end. A `Vec<u8>` is instead written byte by byte,
+/// as a list of parcelables.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct ByteBuf(pub Vec<u8>);
+
+impl Parcelable for ByteBuf {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        let len = parcel.read_i32()?;
+        if len == -1 {
+            return Ok(Self::default());
+        }
+        // Check the length before allocating for it, so a corrupt one cannot exhaust memory
+        if len < 0 || len as usize > parcel.bytes_remaining() {
+            return Err(Error::InvalidLength(len));
+        }
+        let mut data = parcel.read(len as usize)?;
+        data.truncate(len as usize);
+        Ok(Self(data))
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_i32(self.0.len() as i32)?;
+        parcel.write(&self.0)
+    }
+}
+
+/// A fixed size byte array, written like `ByteBuf`. The length read must match `N`.
+impl<const N: usize> Parcelable for [u8; N] {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        let len = parcel.read_i32()?;
+        if len != N as i32 {
+            return Err(Error::InvalidLength(len));
+        }
+        let mut data = [0u8; N];
+        data.copy_from_slice(&parcel.read(N)?[..N]);
+        Ok(data)
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_i32(N as i32)?;
+        parcel.write(self)
+    }
+}
+
+/// An AIDL `ParcelableHolder`, used for extension fields: a stability level and an opaque
+/// sub-parcel, which is only decoded when `get_parcelable` is called. Binder objects within the
+/// sub-parcel are kept, with their offsets relative to the start of `data`.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ParcelableHolder {
+    pub stability: i32,
+    pub data: Parcel,
+}
+
+impl ParcelableHolder {
+    pub fn new(stability: i32) -> Self {
+        Self {
+            stability,
+            data: Parcel::empty(),
+        }
+    }
+
+    /// Replace the held parcelable with `value`, written with `Parcel::write_java_parcelable` under
+    /// `class_name`, as Android's `setParcelable` does
+    pub fn set_parcelable<T: Parcelable>(&mut self, class_name: &str, value: Option<&T>) -> Result<(), Error> {
+        self.data.reset();
+        match value {
+            Some(value) => self.data.write_java_parcelable(class_name, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Decode the held parcelable, if there is one and it was written under `class_name`. Like
+    /// Android's `getParcelable`, a parcelable of another class is `None` rather than an error.
+    pub fn get_parcelable<T: Parcelable>(&self, class_name: &str) -> Result<Option<T>, Error> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let mut data = self.data.clone();
+        data.set_position(0);
+        if data.read_str16_nullable()?.as_deref() != Some(class_name) {
+            return Ok(None);
+        }
+        data.read_nested(T::deserialize).map(Some)
+    }
+}
+
+/// Matches `ParcelableHolder.writeToParcel`/`readFromParcel`: the stability, then the size of the
+/// sub-parcel followed by its data.
+impl Parcelable for ParcelableHolder {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        let stability = parcel.read_i32()?;
+        let size = parcel.read_i32()?;
+        if size < 0 || size as usize > parcel.bytes_remaining() {
+            return Err(Error::InvalidLength(size));
+        }
+        let start = parcel.position() as usize;
+        let end = start + size as usize;
+        let offsets = parcel
+            .offsets()
+            .iter()
+            .filter(|&&offset| offset >= start && offset < end)
+            .map(|offset| offset - start)
+            .collect();
+        let data = parcel.to_slice()[start..end].to_vec();
+        // Also moves past the objects within the sub-parcel, so they are not read again
+        parcel.set_position(end as u64);
+        Ok(Self {
+            stability,
+            data: Parcel::from_parts(data, offsets),
+        })
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_i32(self.stability)?;
+        let size = parcel.write_placeholder_u32()?;
+        let start = parcel.position();
+        parcel.append_parcel(&mut self.data.clone())?;
+        let end = parcel.position();
+        parcel.patch_u32(size, (end - start) as u32)
+    }
+}
+
+/// Decodes one registered parcelable type, see `ParcelableRegistry::register`
+type ParcelableCreator = Box<dyn Fn(&mut Parcel) -> Result<Box<dyn Any>, Error>>;
+
+/// Maps Java class names to the parcelables they decode as, for reading fields whose concrete type
+/// is only known from the class name in front of them (Java's `readParcelable`). See
+/// `Parcel::read_polymorphic`.
+#[derive(Default)]
+pub struct ParcelableRegistry {
+    creators: HashMap<String, ParcelableCreator>,
+}
+
+impl ParcelableRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode parcelables named `class_name`, such as `android.content.ComponentName`, as `T`
+    pub fn register<T: Parcelable + 'static>(&mut self, class_name: &str) {
+        self.creators.insert(
+            class_name.to_string(),
+            Box::new(|parcel| Ok(Box::new(T::deserialize(parcel)?))),
+        );
+    }
+
+    /// Decode a parcelable of class `class_name` from `parcel`
+    pub fn create(&self, class_name: &str, parcel: &mut Parcel) -> Result<Box<dyn Any>, Error> {
+        let creator = self
+            .creators
+            .get(class_name)
+            .ok_or_else(|| Error::UnknownParcelableClass(class_name.to_string()))?;
+        creator(parcel)
+    }
+}
+
+impl Debug for ParcelableRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.creators.keys()).finish()
+    }
+}
+
+/// A file descriptor field. `RawFd` is a plain `i32`, so this wrapper is what selects the binder
+/// file descriptor object encoding, which records an object offset so the driver translates the fd
+/// into the receiving process.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FileDescriptor(pub RawFd);
+
+impl Parcelable for FileDescriptor {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        Ok(Self(parcel.read_file_descriptor()?))
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_file_descriptor(self.0, false)
+    }
+}
+
+macro_rules! implement_ip_address {
+    ($ty:ty, $len:expr) => {
+        /// Written as a byte array of the address' octets, in network order.
+        impl Parcelable for $ty {
+            fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+                let len = parcel.read_i32()?;
+                if len != $len {
+                    return Err(Error::InvalidLength(len));
+                }
+                let mut octets = [0u8; $len];
+                octets.copy_from_slice(&parcel.read($len)?[..$len]);
+                Ok(Self::from(octets))
+            }
+
+            fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+                parcel.write_i32($len)?;
+                parcel.write(&self.octets())
+            }
+        }
+    };
+}
+
+implement_ip_address!(Ipv4Addr, 4);
+implement_ip_address!(Ipv6Addr, 16);
+
 macro_rules! implement_primitve {
     ($ty:ty, $func:ident, $wty:ty, $wfunc:ident) => {
         impl Parcelable for $ty {
@@ -58,6 +360,39 @@ implement_primitve!(i64, read_u64, u64, write_u64);
 implement_primitve!(u64, read_u64, u64, write_u64);
 implement_primitve!(usize, read_usize, usize, write_usize);
 
+macro_rules! implement_nonzero {
+    ($ty:ty, $inner:ty) => {
+        /// Written as the underlying integer. A zero read from the parcel is rejected.
+        impl Parcelable for $ty {
+            fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+                <$ty>::new(<$inner>::deserialize(parcel)?).ok_or(Error::BadEnumValue)
+            }
+
+            fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+                self.get().serialize(parcel)
+            }
+        }
+    };
+}
+
+implement_nonzero!(NonZeroU8, u8);
+implement_nonzero!(NonZeroU16, u16);
+implement_nonzero!(NonZeroI16, i16);
+implement_nonzero!(NonZeroU32, u32);
+implement_nonzero!(NonZeroI32, i32);
+implement_nonzero!(NonZeroU64, u64);
+implement_nonzero!(NonZeroI64, i64);
+
+impl Parcelable for char {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        parcel.read_char16()
+    }
+
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_char16(*self)
+    }
+}
+
 impl Parcelable for () {
     fn deserialize(_parcel: &mut Parcel) -> Result<Self, Error>
     where
@@ -94,6 +429,21 @@ impl Parcelable for String {
         Ok(())
     }
 }
+/// Encoded exactly like `String`, so a borrowed string can be serialized without allocating.
+/// Deserializing always yields `Cow::Owned`.
+impl Parcelable for Cow<'_, str> {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        Ok(Cow::Owned(parcel.read_str()?))
+    }
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        if self.is_empty() {
+            parcel.write_i32(-1)?;
+        } else {
+            parcel.write_str(self)?;
+        }
+        Ok(())
+    }
+}
 impl Parcelable for String16 {
     fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
         Ok(String16(parcel.read_str16()?))
@@ -108,29 +458,45 @@ impl Parcelable for String16 {
     }
 }
 
+macro_rules! implement_tuple {
+    ($($name:ident),+) => {
+        impl<$($name: Parcelable),+> Parcelable for ($($name,)+) {
+            fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+                Ok(($($name::deserialize(parcel)?,)+))
+            }
+
+            #[allow(non_snake_case)]
+            fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+                let ($($name,)+) = self;
+                $($name.serialize(parcel)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+implement_tuple!(A);
+implement_tuple!(A, B);
+implement_tuple!(A, B, C);
+implement_tuple!(A, B, C, D);
+implement_tuple!(A, B, C, D, E);
+implement_tuple!(A, B, C, D, E, F);
+
+/// Uses Android's `@nullable` parcelable convention, a present flag of `1` or `0` before the value,
+/// as written by `Parcel::write_typed_object`. Nullable strings and arrays instead use a length of
+/// `-1`, see `Parcel::write_str16_nullable` and `Parcel::write_vec_nullable`.
 impl<T: Parcelable> Parcelable for Option<T> {
     fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
-        let prefix = parcel.read_i32()?;
-        Ok(if prefix != 0 && prefix != -1 {
-            Some(T::deserialize(parcel)?)
-        } else {
-            None
-        })
+        parcel.read_typed_object()
     }
     fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
-        if let Some(internal) = self {
-            parcel.write_i32(1)?;
-            internal.serialize(parcel)?;
-        } else {
-            parcel.write_i32(0)?;
-        };
-        Ok(())
+        parcel.write_typed_object(self.as_ref())
     }
 }
 
 impl<T: Parcelable> Parcelable for Box<T> {
     fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
-        Ok(Box::new(T::deserialize(parcel)?))
+        Ok(Box::new(parcel.read_nested(T::deserialize)?))
     }
 
     fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
@@ -142,11 +508,38 @@ impl<T: Parcelable> Parcelable for Box<T> {
 impl<T: Parcelable> Parcelable for Vec<T> {
     fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
         let len = parcel.read_i32()? as usize;
-        let mut res = Vec::with_capacity(len);
-        for _ in 0..len {
-            res.push(T::deserialize(parcel)?);
+        parcel.read_nested(|parcel| {
+            let mut res = Vec::with_capacity(len);
+            for _ in 0..len {
+                res.push(T::deserialize(parcel)?);
+            }
+            Ok(res)
+        })
+    }
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        self.as_slice().serialize(parcel)
+    }
+}
+
+/// Written like a `Vec`, so a borrowed slice can be serialized without collecting it first. A
+/// slice can not be deserialized, read a `Vec` instead.
+impl<T: Parcelable> Parcelable for &[T] {
+    fn deserialize(_parcel: &mut Parcel) -> Result<Self, Error> {
+        Err(Error::DeserializationError)
+    }
+    fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
+        parcel.write_i32(self.len() as i32)?;
+        for val in self.iter() {
+            val.serialize(parcel)?;
         }
-        Ok(res)
+        Ok(())
+    }
+}
+
+/// Written like a `Vec`, from front to back
+impl<T: Parcelable> Parcelable for VecDeque<T> {
+    fn deserialize(parcel: &mut Parcel) -> Result<Self, Error> {
+        Ok(Vec::deserialize(parcel)?.into())
     }
     fn serialize(&self, parcel: &mut Parcel) -> Result<(), Error> {
         parcel.write_i32(self.len() as i32)?;
@@ -178,3 +571,589 @@ impl<K: Parcelable + Eq + Hash, V: Parcelable> Parcelable for HashMap<K, V> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::MockDriver, TransactionFlags};
+    use parcelable_derive::Parcelable;
+    use std::ffi::c_void;
+
+    #[derive(Debug, PartialEq, Parcelable)]
+    struct Extension {
+        value: i32,
+    }
+
+    #[test]
+    fn byte_buf_round_trip() {
+        let mut parcel = Parcel::empty();
+        ByteBuf(vec![1, 2, 3]).serialize(&mut parcel).unwrap();
+        parcel.write_i32(9).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(ByteBuf::deserialize(&mut parcel).unwrap(), ByteBuf(vec![1, 2, 3]));
+        assert_eq!(parcel.read_i32().unwrap(), 9);
+    }
+
+    #[test]
+    fn byte_buf_lengths_are_checked() {
+        for len in [-2, i32::MAX, 5] {
+            let mut parcel = Parcel::empty();
+            parcel.write_i32(len).unwrap();
+            parcel.write(&[0; 4]).unwrap();
+            parcel.set_position(0);
+            assert!(matches!(ByteBuf::deserialize(&mut parcel), Err(Error::InvalidLength(l)) if l == len));
+        }
+    }
+
+    #[test]
+    fn parcelable_holder_round_trip() {
+        let mut holder = ParcelableHolder::new(1);
+        holder.set_parcelable("com.example.Extension", Some(&Extension { value: 5 })).unwrap();
+
+        let mut parcel = Parcel::empty();
+        holder.serialize(&mut parcel).unwrap();
+        parcel.write_i32(9).unwrap();
+        parcel.set_position(0);
+
+        let holder = ParcelableHolder::deserialize(&mut parcel).unwrap();
+        assert_eq!(parcel.read_i32().unwrap(), 9);
+        assert_eq!(holder.stability, 1);
+        assert_eq!(
+            holder.get_parcelable::<Extension>("com.example.Extension").unwrap(),
+            Some(Extension { value: 5 })
+        );
+        assert_eq!(holder.get_parcelable::<Extension>("com.example.Other").unwrap(), None);
+    }
+
+    #[test]
+    fn parcelable_holder_skips_its_objects() {
+        let mut holder = ParcelableHolder::new(1);
+        holder.data.write_binder(3 as *const c_void).unwrap();
+
+        let mut parcel = Parcel::empty();
+        holder.serialize(&mut parcel).unwrap();
+        parcel.write_binder(2 as *const c_void).unwrap();
+        parcel.set_position(0);
+
+        let holder = ParcelableHolder::deserialize(&mut parcel).unwrap();
+        assert_eq!(holder.data.offsets(), &[0]);
+        assert_eq!(parcel.read_strong_binder().unwrap().handle, 2);
+    }
+    #[cfg(feature = "parcel-trace")]
+    #[test]
+    fn trace_attribute_traces_each_field() {
+        use crate::testing::capture_trace;
+
+        #[derive(Debug, Parcelable)]
+        #[parcelable(trace)]
+        struct Point {
+            x: i32,
+            y: i32,
+        }
+
+        let mut parcel = Parcel::empty();
+        let written = capture_trace(|| Point { x: 1, y: 2 }.serialize(&mut parcel).unwrap());
+        parcel.set_position(0);
+        let read = capture_trace(|| {
+            Point::deserialize(&mut parcel).unwrap();
+        });
+
+        for lines in [written, read] {
+            let fields: Vec<_> = lines.iter().filter(|line| line.contains("Point.")).collect();
+            assert_eq!(fields.len(), 2, "{:?}", lines);
+            assert!(fields[0].ends_with("::Point.x = 1 @ offset 0..4"), "{}", fields[0]);
+            assert!(fields[1].ends_with("::Point.y = 2 @ offset 4..8"), "{}", fields[1]);
+        }
+    }
+
+    #[test]
+    fn component_name_as_typed_object() {
+        let component = ComponentName::new("com.example", "com.example.Receiver");
+        let mut parcel = Parcel::empty();
+        parcel.write_typed_object(Some(&component)).unwrap();
+        parcel.write_typed_object::<ComponentName>(None).unwrap();
+
+        // `createFromParcel` reads the package then the class, after the present flag
+        let mut expected = Parcel::empty();
+        expected.write_i32(1).unwrap();
+        expected.write_str16("com.example").unwrap();
+        expected.write_str16("com.example.Receiver").unwrap();
+        expected.write_i32(0).unwrap();
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+
+        parcel.set_position(0);
+        assert_eq!(parcel.read_typed_object().unwrap(), Some(component));
+        assert_eq!(parcel.read_typed_object::<ComponentName>().unwrap(), None);
+    }
+
+    #[test]
+    fn stable_parcelable_skips_newer_fields() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        #[parcelable(stable)]
+        struct Old {
+            a: i32,
+            b: String,
+        }
+
+        #[derive(Debug, PartialEq, Parcelable)]
+        #[parcelable(stable)]
+        struct New {
+            a: i32,
+            b: String,
+            c: i64,
+        }
+
+        let mut parcel = Parcel::empty();
+        New { a: 1, b: "b".to_string(), c: 3 }.serialize(&mut parcel).unwrap();
+        parcel.write_i32(9).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(Old::deserialize(&mut parcel).unwrap(), Old { a: 1, b: "b".to_string() });
+        assert_eq!(parcel.read_i32().unwrap(), 9);
+    }
+
+    #[test]
+    fn flattened_fields_are_inlined() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Child {
+            b: i32,
+            c: String,
+        }
+
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Parent {
+            a: i32,
+            #[parcelable(flatten)]
+            child: Child,
+            d: i64,
+        }
+
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Inlined {
+            a: i32,
+            b: i32,
+            c: String,
+            d: i64,
+        }
+
+        let parent = Parent {
+            a: 1,
+            child: Child { b: 2, c: "c".to_string() },
+            d: 4,
+        };
+        let mut parcel = Parcel::empty();
+        parent.serialize(&mut parcel).unwrap();
+        let mut inlined = Parcel::empty();
+        Inlined { a: 1, b: 2, c: "c".to_string(), d: 4 }.serialize(&mut inlined).unwrap();
+        assert_eq!(parcel.to_slice(), inlined.to_slice());
+
+        parcel.set_position(0);
+        assert_eq!(Parent::deserialize(&mut parcel).unwrap(), parent);
+    }
+
+    #[test]
+    fn fd_field_round_trip_through_a_transaction() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Socket {
+            fd: FileDescriptor,
+            port: u16,
+            address: Ipv4Addr,
+        }
+
+        let socket = Socket {
+            fd: FileDescriptor(7),
+            port: 8080,
+            address: Ipv4Addr::new(127, 0, 0, 1),
+        };
+        let mut reply = Parcel::empty();
+        socket.serialize(&mut reply).unwrap();
+        // The fd object is recorded, so the driver would translate it
+        assert_eq!(reply.offsets(), &[0]);
+
+        // The mock passes the fd through as it is
+        let (driver, mut binder) = MockDriver::new();
+        driver.queue_reply(&reply);
+        let (_, mut parcel) = binder.transact(1, 1, TransactionFlags::empty(), &mut Parcel::empty()).unwrap();
+        assert_eq!(Socket::deserialize(&mut parcel).unwrap(), socket);
+        assert!(!parcel.has_unread_data());
+    }
+
+    #[test]
+    fn nested_option_round_trip() {
+        let values: [Option<Option<i32>>; 3] = [None, Some(None), Some(Some(5))];
+        let mut parcel = Parcel::empty();
+        for value in &values {
+            value.serialize(&mut parcel).unwrap();
+        }
+        // Each level writes its own present flag, 1 or 0, like `writeTypedObject`
+        let mut expected = Parcel::empty();
+        for word in [0, 1, 0, 1, 1, 5] {
+            expected.write_i32(word).unwrap();
+        }
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+
+        parcel.set_position(0);
+        for value in values {
+            assert_eq!(Option::<Option<i32>>::deserialize(&mut parcel).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn non_zero_and_char_round_trip() {
+        let mut parcel = Parcel::empty();
+        NonZeroU32::new(7).unwrap().serialize(&mut parcel).unwrap();
+        NonZeroI64::new(-8).unwrap().serialize(&mut parcel).unwrap();
+        'é'.serialize(&mut parcel).unwrap();
+        0u32.serialize(&mut parcel).unwrap();
+        parcel.set_position(0);
+
+        assert_eq!(NonZeroU32::deserialize(&mut parcel).unwrap().get(), 7);
+        assert_eq!(NonZeroI64::deserialize(&mut parcel).unwrap().get(), -8);
+        assert_eq!(char::deserialize(&mut parcel).unwrap(), 'é');
+        assert!(matches!(NonZeroU32::deserialize(&mut parcel), Err(Error::BadEnumValue)));
+
+        // A char outside of the BMP does not fit in a single UTF-16 code unit
+        assert!(matches!('😀'.serialize(&mut Parcel::empty()), Err(Error::InvalidChar(0x1f600))));
+    }
+
+    #[test]
+    fn untagged_enum_matches_its_fields() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        #[parcelable(untagged)]
+        enum Wrapper {
+            Point { x: i32, label: String },
+        }
+
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Point {
+            x: i32,
+            label: String,
+        }
+
+        let wrapper = Wrapper::Point { x: 1, label: "a".to_string() };
+        let mut parcel = Parcel::empty();
+        wrapper.serialize(&mut parcel).unwrap();
+        let mut plain = Parcel::empty();
+        Point { x: 1, label: "a".to_string() }.serialize(&mut plain).unwrap();
+        assert_eq!(parcel.to_slice(), plain.to_slice());
+
+        parcel.set_position(0);
+        assert_eq!(Wrapper::deserialize(&mut parcel).unwrap(), wrapper);
+    }
+
+    #[test]
+    fn borrowed_string_matches_owned() {
+        let mut borrowed = Parcel::empty();
+        Cow::Borrowed("héllo").serialize(&mut borrowed).unwrap();
+        let mut owned = Parcel::empty();
+        "héllo".to_string().serialize(&mut owned).unwrap();
+        assert_eq!(borrowed.to_slice(), owned.to_slice());
+
+        borrowed.set_position(0);
+        assert!(matches!(Cow::<str>::deserialize(&mut borrowed).unwrap(), Cow::Owned(string) if string == "héllo"));
+    }
+
+    #[test]
+    fn over_nested_parcel_is_rejected() {
+        #[derive(Debug, Parcelable)]
+        struct Node {
+            next: Option<Box<Node>>,
+        }
+
+        // Each present flag opens another level
+        let mut parcel = Parcel::empty();
+        for _ in 0..crate::DEFAULT_MAX_DEPTH * 2 {
+            parcel.write_i32(1).unwrap();
+        }
+        parcel.set_position(0);
+        assert!(matches!(Node::deserialize(&mut parcel), Err(Error::RecursionLimitExceeded)));
+
+        // Up to the limit is fine
+        let mut parcel = Parcel::empty();
+        for _ in 0..3 {
+            parcel.write_i32(1).unwrap();
+        }
+        parcel.write_i32(0).unwrap();
+        parcel.set_position(0);
+        parcel.set_max_depth(6);
+        assert!(Node::deserialize(&mut parcel).is_ok());
+        parcel.set_position(0);
+        parcel.set_max_depth(5);
+        assert!(matches!(Node::deserialize(&mut parcel), Err(Error::RecursionLimitExceeded)));
+    }
+
+    #[test]
+    fn versioned_parcelable_defaults_newer_fields() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        #[parcelable(version_field = "version")]
+        struct Config {
+            version: i32,
+            name: String,
+            #[parcelable(since = 2)]
+            retries: i32,
+        }
+
+        // A version 1 sender writes no `retries`
+        let mut parcel = Parcel::empty();
+        parcel.write_i32(1).unwrap();
+        parcel.write_str("v1").unwrap();
+        parcel.write_i32(9).unwrap();
+        parcel.set_position(0);
+        assert_eq!(
+            Config::deserialize(&mut parcel).unwrap(),
+            Config { version: 1, name: "v1".to_string(), retries: 0 }
+        );
+        assert_eq!(parcel.read_i32().unwrap(), 9);
+
+        let config = Config { version: 2, name: "v2".to_string(), retries: 3 };
+        let mut parcel = Parcel::empty();
+        config.serialize(&mut parcel).unwrap();
+        parcel.set_position(0);
+        assert_eq!(Config::deserialize(&mut parcel).unwrap(), config);
+    }
+
+    #[test]
+    fn unknown_discriminant_is_kept_in_other() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        enum Mode {
+            Off,
+            #[parcelable(discriminator = 5)]
+            On,
+            #[parcelable(other)]
+            Unknown(i32),
+        }
+
+        let mut parcel = Parcel::empty();
+        for discriminant in [0, 5, 3] {
+            parcel.write_i32(discriminant).unwrap();
+        }
+        parcel.set_position(0);
+        let modes = (0..3).map(|_| Mode::deserialize(&mut parcel).unwrap()).collect::<Vec<_>>();
+        assert_eq!(modes, [Mode::Off, Mode::On, Mode::Unknown(3)]);
+
+        let mut written = Parcel::empty();
+        for mode in &modes {
+            mode.serialize(&mut written).unwrap();
+        }
+        assert_eq!(written.to_slice(), parcel.to_slice());
+    }
+
+    #[test]
+    fn nullable_string_field_matches_aidl() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Label {
+            id: i32,
+            #[parcelable(nullable_string)]
+            text: Option<String>,
+        }
+
+        // A `@nullable String` as AIDL writes it: a str16, or a length of -1 for null
+        let cases: [(Label, &[u8]); 3] = [
+            (Label { id: 1, text: Some("ok".to_string()) }, &[1, 0, 0, 0, 2, 0, 0, 0, b'o', 0, b'k', 0, 0, 0, 0, 0]),
+            (Label { id: 2, text: Some(String::new()) }, &[2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            (Label { id: 3, text: None }, &[3, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]),
+        ];
+        for (label, bytes) in cases {
+            let mut parcel = Parcel::empty();
+            label.serialize(&mut parcel).unwrap();
+            assert_eq!(parcel.to_slice(), bytes, "{:?}", label);
+            assert_eq!(Label::deserialize(&mut Parcel::from_slice(bytes)).unwrap(), label);
+        }
+    }
+
+    #[test]
+    fn polymorphic_parcelables_decode_by_class_name() {
+        let mut registry = ParcelableRegistry::new();
+        registry.register::<ComponentName>("android.content.ComponentName");
+        registry.register::<Extension>("com.example.Extension");
+
+        let component = ComponentName::new("com.example", "com.example.Receiver");
+        let mut parcel = Parcel::empty();
+        parcel.write_polymorphic("com.example.Extension", &Extension { value: 4 }).unwrap();
+        parcel.write_polymorphic("android.content.ComponentName", &component).unwrap();
+        parcel.write_polymorphic("com.example.Unknown", &Extension { value: 5 }).unwrap();
+
+        parcel.set_position(0);
+        let first = parcel.read_polymorphic(&registry).unwrap();
+        assert_eq!(first.downcast_ref::<Extension>(), Some(&Extension { value: 4 }));
+        let second = parcel.read_polymorphic(&registry).unwrap();
+        assert_eq!(second.downcast_ref::<ComponentName>(), Some(&component));
+        assert!(matches!(
+            parcel.read_polymorphic(&registry),
+            Err(Error::UnknownParcelableClass(name)) if name == "com.example.Unknown"
+        ));
+    }
+
+    #[test]
+    fn tagged_trait_field_round_trip() {
+        trait Animal: TaggedParcelable {
+            fn legs(&self) -> i32;
+        }
+
+        #[derive(Debug, Parcelable)]
+        struct Bird {
+            wingspan: i32,
+        }
+
+        impl TaggedParcelable for Bird {
+            fn tag(&self) -> i32 {
+                1
+            }
+        }
+
+        impl Animal for Bird {
+            fn legs(&self) -> i32 {
+                2
+            }
+        }
+
+        #[derive(Debug, Parcelable)]
+        struct Dog {
+            name: String,
+        }
+
+        impl TaggedParcelable for Dog {
+            fn tag(&self) -> i32 {
+                2
+            }
+        }
+
+        impl Animal for Dog {
+            fn legs(&self) -> i32 {
+                4
+            }
+        }
+
+        fn create_animal(tag: i32, parcel: &mut Parcel) -> Result<Box<dyn Animal>, Error> {
+            Ok(match tag {
+                1 => Box::new(Bird::deserialize(parcel)?),
+                2 => Box::new(Dog::deserialize(parcel)?),
+                _ => return Err(Error::BadEnumValue),
+            })
+        }
+
+        #[derive(Debug, Parcelable)]
+        struct Pair {
+            #[parcelable(tagged_trait = "create_animal")]
+            first: Box<dyn Animal>,
+            #[parcelable(tagged_trait = "create_animal")]
+            second: Box<dyn Animal>,
+        }
+
+        let pair = Pair {
+            first: Box::new(Dog { name: "rex".to_string() }),
+            second: Box::new(Bird { wingspan: 30 }),
+        };
+        let mut parcel = Parcel::empty();
+        pair.serialize(&mut parcel).unwrap();
+
+        // Each field is its tag followed by the concrete type
+        let mut expected = Parcel::empty();
+        expected.write_i32(2).unwrap();
+        Dog { name: "rex".to_string() }.serialize(&mut expected).unwrap();
+        expected.write_i32(1).unwrap();
+        expected.write_i32(30).unwrap();
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+
+        parcel.set_position(0);
+        let pair = Pair::deserialize(&mut parcel).unwrap();
+        assert_eq!((pair.first.legs(), pair.second.legs()), (4, 2));
+        assert_eq!(format!("{:?}", pair.second), "Bird { wingspan: 30 }");
+
+        parcel.set_position(0);
+        parcel.write_i32(3).unwrap();
+        parcel.set_position(0);
+        assert!(matches!(Pair::deserialize(&mut parcel), Err(Error::BadEnumValue)));
+    }
+
+    #[test]
+    fn vec_deque_round_trip() {
+        // Pushed at the front too, so front to back is not the order of the underlying buffer
+        let mut values = VecDeque::new();
+        values.push_back(2);
+        values.push_back(3);
+        values.push_front(1);
+        let mut parcel = Parcel::empty();
+        values.serialize(&mut parcel).unwrap();
+
+        let mut expected = Parcel::empty();
+        vec![1, 2, 3].serialize(&mut expected).unwrap();
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+        parcel.set_position(0);
+        assert_eq!(VecDeque::<i32>::deserialize(&mut parcel).unwrap(), values);
+    }
+
+    #[test]
+    fn slice_is_written_like_a_vec() {
+        let values = vec![ComponentName::new("a", "b"), ComponentName::new("c", "d")];
+        let mut from_slice = Parcel::empty();
+        (&values[..]).serialize(&mut from_slice).unwrap();
+        let mut from_vec = Parcel::empty();
+        values.serialize(&mut from_vec).unwrap();
+        assert_eq!(from_slice.to_slice(), from_vec.to_slice());
+
+        from_slice.set_position(0);
+        assert_eq!(Vec::<ComponentName>::deserialize(&mut from_slice).unwrap(), values);
+    }
+
+    #[test]
+    fn transparent_newtype_is_its_inner_value() {
+        #[derive(Debug, PartialEq, Clone, Copy, Parcelable)]
+        #[parcelable(transparent)]
+        struct Uid(u32);
+
+        let mut parcel = Parcel::empty();
+        Uid(5).serialize(&mut parcel).unwrap();
+        let mut expected = Parcel::empty();
+        5u32.serialize(&mut expected).unwrap();
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+
+        parcel.set_position(0);
+        assert_eq!(u32::deserialize(&mut parcel).unwrap(), 5);
+        parcel.set_position(0);
+        assert_eq!(Uid::deserialize(&mut parcel).unwrap(), Uid(5));
+
+        assert_eq!(Uid::from(7), Uid(7));
+        assert_eq!(u32::from(Uid(7)), 7);
+    }
+
+    #[test]
+    fn java_parcelable_round_trip() {
+        let component = ComponentName::new("com.example", "com.example.Receiver");
+        let mut parcel = Parcel::empty();
+        parcel.write_java_parcelable("android.content.ComponentName", &component).unwrap();
+        parcel.write_str16_nullable(None).unwrap();
+
+        // The class name, then the body with nothing in between
+        let mut expected = Parcel::empty();
+        expected.write_str16("android.content.ComponentName").unwrap();
+        component.serialize(&mut expected).unwrap();
+        expected.write_i32(-1).unwrap();
+        assert_eq!(parcel.to_slice(), expected.to_slice());
+
+        parcel.set_position(0);
+        let (class_name, read) = parcel.read_java_parcelable::<ComponentName>().unwrap();
+        assert_eq!(class_name, "android.content.ComponentName");
+        assert_eq!(read, component);
+        // A null parcelable
+        assert!(matches!(parcel.read_java_parcelable::<ComponentName>(), Err(Error::DeserializationError)));
+    }
+
+    #[test]
+    fn u64_after_an_i32_is_only_four_byte_aligned() {
+        #[derive(Debug, PartialEq, Parcelable)]
+        struct Sample {
+            count: i32,
+            timestamp: u64,
+        }
+
+        // As Android's `writeInt32` then `writeUint64` lay it out, without padding in between
+        let bytes = [3, 0, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1];
+        let sample = Sample { count: 3, timestamp: 0x0102_0304_0506_0708 };
+        let mut parcel = Parcel::empty();
+        sample.serialize(&mut parcel).unwrap();
+        assert_eq!(parcel.to_slice(), bytes);
+        assert_eq!(Sample::deserialize(&mut Parcel::from_slice(&bytes)).unwrap(), sample);
+    }
+}
@@ -1,14 +1,69 @@
 use crate::{
     Error,
-    binder::{Binder, BinderFlatObject, Transaction, TransactionFlags},
+    binder::{
+        wait_for_work, Binder, BinderFlatObject, BinderType, Transaction, TransactionFlags, Transport,
+        PERMISSION_DENIED, UNKNOWN_TRANSACTION,
+    },
     parcel::Parcel,
     parcelable::Parcelable,
 };
 
 use std::ffi::c_void;
-use std::marker::PhantomData;
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError,
+};
+
+use std::convert::TryFrom;
 
-use num_traits::FromPrimitive;
+bitflags! {
+    /// The dump priorities a service is registered with, which select the `dumpsys --priority` runs
+    /// that include it. These are `IServiceManager`'s `DUMP_FLAG_*` values.
+    pub struct DumpPriority: u32 {
+        const CRITICAL = 1 << 0;
+        const HIGH = 1 << 1;
+        const NORMAL = 1 << 2;
+        /// The priority Android registers services with when none is given
+        const DEFAULT = 1 << 3;
+        const ALL = Self::CRITICAL.bits | Self::HIGH.bits | Self::NORMAL.bits | Self::DEFAULT.bits;
+        /// The service supports dumping in protobuf format, with `dumpsys --proto`
+        const PROTO = 1 << 4;
+    }
+}
+
+/// Lock a shared binder connection. A panic while it was locked leaves nothing half-updated that
+/// the next user could trip over (at worst some queued commands, see `Binder::reset`), so a
+/// poisoned lock is simply taken over.
+fn lock(binder: &Mutex<Binder>) -> MutexGuard<'_, Binder> {
+    binder.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Make a transaction through a shared connection, like `Binder::transact`, returning the reply.
+/// The connection is only locked while exchanging commands with the driver, not while waiting for
+/// the reply, so that other threads, such as one running a `ServiceListener` which the remote calls
+/// back, can use it meanwhile.
+fn transact(
+    binder: &Mutex<Binder>,
+    handle: i32,
+    code: u32,
+    flags: TransactionFlags,
+    data: &mut Parcel,
+) -> Result<Parcel, Error> {
+    let (mut pending, transport) = {
+        let mut binder = lock(binder);
+        (binder.send_transaction(handle, 0, code, flags, data)?, binder.transport())
+    };
+    loop {
+        if let Some(result) = lock(binder).check_transaction(&mut pending) {
+            return result.map(|(_, parcel)| parcel);
+        }
+        let has_work = wait_for_work(&*transport, pending.deadline());
+        lock(binder).read_reply(&mut pending, has_work);
+    }
+}
 
 const SERVICE_MANAGER_HANDLE: i32 = 0;
 const SERVICE_MANAGER_INTERFACE_TOKEN: &str = "android.os.IServiceManager";
@@ -21,33 +76,47 @@ enum ServiceManagerFunctions {
 }
 
 pub struct Service<'a> {
-    service_manager: &'a mut ServiceManager<'a>,
+    binder: Arc<Mutex<Binder>>,
     handle: i32,
     _name: &'a str,
     interface_name: &'a str,
+    // The encoded interface token, which starts every call, kept so it is only encoded once
+    interface_token: Option<Vec<u8>>,
 }
 
 impl<'a> Service<'a> {
-    pub fn new(service_manager: &'a mut ServiceManager<'a>, _name: &'a str, interface_name: &'a str, handle: i32) -> Self {
+    pub fn new(binder: Arc<Mutex<Binder>>, _name: &'a str, interface_name: &'a str, handle: i32) -> Self {
         Self {
-            service_manager,
+            binder,
             _name,
             interface_name,
             handle,
+            interface_token: None,
         }
     }
     pub fn call(&mut self, function_index: u32, data: &mut Parcel) -> Result<Parcel, Error> {
-        let mut parcel = Parcel::empty();
-        parcel.write_interface_token(self.interface_name)?;
-        if !data.is_empty() {
-            parcel.append_parcel(data)?;
-        };
+        self.call_with_flags(function_index, TransactionFlags::empty(), data)
+    }
 
-        let (_, mut parcel) = self
-            .service_manager
-            .binder
-            .transact(self.handle, function_index, TransactionFlags::AcceptFds |TransactionFlags::CollectNotedAppOps, &mut parcel)?;
+    /// Like `call`, with extra transaction flags, such as `TransactionFlags::ClearBuf` for calls
+    /// carrying secrets.
+    pub fn call_with_flags(
+        &mut self,
+        function_index: u32,
+        flags: TransactionFlags,
+        data: &mut Parcel,
+    ) -> Result<Parcel, Error> {
+        let mut parcel = self.request(data)?;
+        let mut parcel = transact(
+            &self.binder,
+            self.handle,
+            function_index,
+            TransactionFlags::AcceptFds | TransactionFlags::CollectNotedAppOps | flags,
+            &mut parcel,
+        )?;
 
+        // We ask for noted app-ops to be collected, so they may come ahead of the status
+        parcel.read_noted_app_ops()?;
         let status = parcel.read_u32()?;
         if status != 0 {
             panic!(
@@ -61,10 +130,121 @@ impl<'a> Service<'a> {
 
         Ok(parcel)
     }
+
+    /// Call a `oneway` method. The driver returns as soon as the transaction is queued and the
+    /// service sends no reply, so there is no status to check.
+    pub fn call_oneway(&mut self, function_index: u32, data: &mut Parcel) -> Result<(), Error> {
+        let mut parcel = self.request(data)?;
+        transact(&self.binder, self.handle, function_index, TransactionFlags::OneWay | TransactionFlags::AcceptFds, &mut parcel)?;
+        Ok(())
+    }
+
+    /// Build the request for a call: the interface token followed by `data`
+    fn request(&mut self, data: &mut Parcel) -> Result<Parcel, Error> {
+        if self.interface_token.is_none() {
+            let mut token = Parcel::empty();
+            token.write_interface_token(self.interface_name)?;
+            self.interface_token = Some(token.into_vec());
+        }
+        let interface_token = self.interface_token.as_ref().unwrap();
+
+        let mut request = Vec::with_capacity(interface_token.len() + data.len());
+        request.extend_from_slice(interface_token);
+        let mut parcel = Parcel::from_parts(request, vec![]);
+        // The work source, which follows the strict mode policy, is per thread, so it can not be
+        // cached with the rest of the token
+        parcel.write_u32_at(4, Binder::work_source_uid() as u32)?;
+        parcel.set_position(parcel.len() as u64);
+        if !data.is_empty() {
+            parcel.append_parcel(data)?;
+        };
+        Ok(parcel)
+    }
+
+    /// The handle the driver assigned to the remote object in this process
+    pub fn handle(&self) -> i32 {
+        self.handle
+    }
+
+    /// The connection to the binder driver, for operations on the handle outside of `call`, such as
+    /// a raw `transact`. Reference counts taken with `add_ref`/`acquire` must be balanced with
+    /// `dec_ref`/`release`, and those taken by `ServiceManager::get_service` must be left alone, or
+    /// the driver may free the handle while the service is still in use. The connection is shared,
+    /// so the returned guard must be dropped before calling through any service again.
+    pub fn binder(&self) -> MutexGuard<'_, Binder> {
+        lock(&self.binder)
+    }
+
+    /// Query the remote object for the interface descriptor it implements, using
+    /// `INTERFACE_TRANSACTION`.
+    pub fn interface_descriptor(&mut self) -> Result<String, Error> {
+        let mut parcel = transact(
+            &self.binder,
+            self.handle,
+            Transaction::Interface as u32,
+            TransactionFlags::empty(),
+            &mut Parcel::empty(),
+        )?;
+
+        parcel.read_str16()
+    }
+
+    /// Narrow this service to another interface implemented by the same remote object, such as a
+    /// derived interface. The handle is kept, but `interface_name` is used as the interface token
+    /// in subsequent calls.
+    pub fn cast(self, interface_name: &'a str) -> Service<'a> {
+        Service {
+            interface_name,
+            interface_token: None,
+            ..self
+        }
+    }
+
+    /// Like `cast`, but also verifies that the remote object reports `interface_name` as its
+    /// interface descriptor, returning `Error::InterfaceMismatch` if it does not.
+    pub fn cast_checked(self, interface_name: &'a str) -> Result<Service<'a>, Error> {
+        let mut service = self.cast(interface_name);
+        let actual = service.interface_descriptor()?;
+        if actual != interface_name {
+            return Err(Error::InterfaceMismatch {
+                expected: interface_name.to_string(),
+                actual,
+            });
+        }
+
+        Ok(service)
+    }
+}
+
+/// A typed client-side proxy for a binder interface. Each AIDL method can be implemented as a
+/// single `call_method` invocation, using a tuple for methods which take several arguments.
+pub trait Proxy {
+    /// Serialize `args`, call the method identified by `code` and deserialize its result from
+    /// the reply, after the status.
+    fn call_method<A: Parcelable, R: Parcelable>(&mut self, code: u32, args: &A) -> Result<R, Error>;
+}
+
+impl<'a> Proxy for Service<'a> {
+    fn call_method<A: Parcelable, R: Parcelable>(&mut self, code: u32, args: &A) -> Result<R, Error> {
+        let mut parcel = Parcel::empty();
+        args.serialize(&mut parcel)?;
+        let mut reply = self.call(code, &mut parcel)?;
+        R::deserialize(&mut reply)
+    }
 }
 
 pub trait BinderService {
     fn process_request(&self, code: u32, data: &mut Parcel) -> Parcel;
+
+    /// Produce the text shown by `dumpsys` for this service. `args` are the arguments passed to
+    /// `dumpsys` after the service name.
+    fn dump(&self, _args: &[String]) -> String {
+        String::new()
+    }
+
+    /// Called when the system notifies services that system properties have changed, such as
+    /// debug flags. The notification is acknowledged whatever this does.
+    fn on_sysprops_changed(&self) {}
 }
 
 pub struct ServiceListener<'a, BS>
@@ -72,52 +252,168 @@ where
     BS: BinderService,
 {
     service_delegate: &'a BS,
-    service_manager: &'a mut ServiceManager<'a>,
+    binder: Arc<Mutex<Binder>>,
+    // For waiting for transactions without locking the connection
+    transport: Arc<dyn Transport>,
     _name: &'a str,
     interface_name: &'a str,
+    stopped: Arc<AtomicBool>,
+    cookie: u64,
+    // Reused for the replies the listener builds itself, to avoid an allocation per transaction.
+    reply: Parcel,
 }
 
 impl<'a, BS> ServiceListener<'a, BS>
 where
     BS: BinderService,
 {
-    pub fn new(service_delegate: &'a BS, service_manager: &'a mut ServiceManager<'a>, _name: &'a str, interface_name: &'a str) -> Self {
+    pub fn new(service_delegate: &'a BS, binder: Arc<Mutex<Binder>>, _name: &'a str, interface_name: &'a str) -> Self {
+        let transport = lock(&binder).transport();
         Self {
             service_delegate,
-            service_manager,
+            binder,
+            transport,
             _name,
             interface_name,
+            stopped: Arc::new(AtomicBool::new(false)),
+            cookie: 0,
+            reply: Parcel::empty(),
         }
     }
 
+    /// Process incoming transactions until `stop` is called. The flag is checked between
+    /// transactions, so `run` returns after the transaction it is waiting for has been handled.
     pub fn run(&mut self) -> Result<(), Error>{
-        loop {
-            let (transaction, mut parcel) = self.service_manager.binder.do_write_read(&mut Parcel::empty())?;
-            if let Some(transaction) = transaction {
-                if transaction.code() >= Transaction::FirstCall as u32 && transaction.code() <= Transaction::LastCall as u32 {
-                    assert!(parcel.read_interface_token()? == self.interface_name);
-                    self.service_manager.binder.reply(&mut self.service_delegate.process_request(transaction.code(), &mut parcel), transaction.flags())?;
-                } else if let Transaction::Interface =  Transaction::from_u32(transaction.code()).unwrap() {
-                    let mut parcel = Parcel::empty();
-                    parcel.write_u32(0)?;
-                    parcel.write_str16(self.interface_name)?;
-                    self.service_manager.binder.reply(&mut parcel, transaction.flags() | TransactionFlags::AcceptFds)?;
+        while !self.stopped.load(Ordering::SeqCst) {
+            self.process_incoming()?;
+        }
+        Ok(())
+    }
+
+    /// Process a single incoming transaction, if one is pending, without blocking. Returns whether
+    /// a transaction was handled, which allows the listener to be driven from another event loop.
+    pub fn run_once(&mut self) -> Result<bool, Error> {
+        {
+            // The driver only hands a thread the process's work once it has entered the looper
+            let mut binder = lock(&self.binder);
+            binder.enter_looper()?;
+            if !binder.poll(0)? {
+                return Ok(false);
+            }
+        }
+        self.process_incoming()
+    }
+
+    /// Ask `run` to return once the current transaction has been handled.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+
+    /// Set the cookie the service's binder object was published with. Transactions carrying any
+    /// other cookie are not meant for this service, and are answered with `UNKNOWN_TRANSACTION`.
+    pub fn with_cookie(mut self, cookie: u64) -> Self {
+        self.cookie = cookie;
+        self
+    }
+
+    /// The cookie identifying the service's binder object
+    pub fn cookie(&self) -> u64 {
+        self.cookie
+    }
+
+    /// Retrieve the flag used by `stop`, so that the listener can be stopped from elsewhere, such as
+    /// another thread or a signal handler.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stopped.clone()
+    }
+
+    /// Answer a transaction which can not be handled with `status`
+    fn reply_status(&mut self, status: i32, flags: TransactionFlags) -> Result<(), Error> {
+        // One-way transactions have nobody waiting for a reply
+        if !flags.contains(TransactionFlags::OneWay) {
+            self.reply.clear();
+            self.reply.write_i32(status)?;
+            lock(&self.binder).reply(&mut self.reply, TransactionFlags::StatusCode)?;
+        }
+        Ok(())
+    }
+
+    fn process_incoming(&mut self) -> Result<bool, Error> {
+        // The looper is per thread, so it is entered by whichever thread handles the transactions,
+        // before waiting for them
+        lock(&self.binder).enter_looper()?;
+        // The connection is shared, so it is only locked once there is a transaction to read
+        if !wait_for_work(&*self.transport, None)? {
+            return Ok(false);
+        }
+        let (transaction, mut parcel) = lock(&self.binder).do_write_read(&mut Parcel::empty())?;
+        if let Some(transaction) = transaction {
+            if transaction.cookie() != self.cookie {
+                self.reply_status(UNKNOWN_TRANSACTION, transaction.flags())?;
+            } else if transaction.code() >= Transaction::FirstCall as u32 && transaction.code() <= Transaction::LastCall as u32 {
+                // Like Android's `CHECK_INTERFACE`, refuse calls meant for another interface, or whose
+                // token can not even be read, rather than misreading their arguments. Only the header
+                // and descriptor are checked, the strict mode policy and work source are the caller's.
+                if !matches!(parcel.read_interface_token(), Ok(name) if name == self.interface_name) {
+                    self.reply_status(PERMISSION_DENIED, transaction.flags())?;
+                } else {
+                    // The delegate may call other services through the same connection, so it must
+                    // not be locked until the reply is ready
+                    let mut reply = self.service_delegate.process_request(transaction.code(), &mut parcel);
+                    lock(&self.binder).reply(&mut reply, transaction.flags())?;
+                }
+            } else {
+                match Transaction::try_from(transaction.code()) {
+                    Ok(Transaction::Interface) => {
+                        self.reply.clear();
+                        self.reply.write_str16(self.interface_name)?;
+                        lock(&self.binder).reply(&mut self.reply, transaction.flags() | TransactionFlags::AcceptFds)?;
+                    }
+                    Ok(Transaction::Dump) => {
+                        let fd = parcel.read_dump_fd()?;
+                        let argc = parcel.read_i32()?;
+                        let args = (0..argc).map(|_| parcel.read_str16()).collect::<Result<Vec<_>, _>>()?;
+                        // The dump output goes to the fd we were given, which we own and close
+                        // once done, while the reply itself is empty.
+                        let mut file = unsafe { File::from_raw_fd(fd) };
+                        file.write_all(self.service_delegate.dump(&args).as_bytes())?;
+                        self.reply.clear();
+                        lock(&self.binder).reply(&mut self.reply, transaction.flags())?;
+                    }
+                    Ok(Transaction::Sysprops) => {
+                        self.service_delegate.on_sysprops_changed();
+                        self.reply.clear();
+                        lock(&self.binder).reply(&mut self.reply, transaction.flags())?;
+                    }
+                    Ok(Transaction::Ping) => {
+                        self.reply.clear();
+                        lock(&self.binder).reply(&mut self.reply, transaction.flags())?;
+                    }
+                    _ => self.reply_status(UNKNOWN_TRANSACTION, transaction.flags())?,
                 }
             }
+            Ok(true)
+        } else {
+            Ok(false)
         }
     }
 }
 
-pub struct ServiceManager<'a> {
-    binder: Binder,
-    _phantom: &'a PhantomData<Binder>
+/// The service manager, which owns the process' connection to the binder driver. The connection is
+/// shared with every `Service` obtained from it, so several services can be used at once.
+pub struct ServiceManager {
+    binder: Arc<Mutex<Binder>>,
 }
 
-impl<'a> ServiceManager<'a> {
+impl ServiceManager {
     pub fn new() -> Result<Self, Error> {
+        Self::with_binder(Binder::new())
+    }
+
+    /// Use `binder` as the connection to the driver, such as one set up with `Binder::from_fd`
+    pub fn with_binder(binder: Binder) -> Result<Self, Error> {
         let mut service_manager = Self {
-            binder: Binder::new(),
-            _phantom: &PhantomData,
+            binder: Arc::new(Mutex::new(binder)),
         };
 
         service_manager.ping()?;
@@ -127,7 +423,8 @@ impl<'a> ServiceManager<'a> {
 
     fn ping(&mut self) -> Result<(), Error>{
         let mut parcel = Parcel::empty();
-        self.binder.transact(
+        transact(
+            &self.binder,
             SERVICE_MANAGER_HANDLE,
             Transaction::Ping as u32,
             TransactionFlags::empty(),
@@ -136,11 +433,24 @@ impl<'a> ServiceManager<'a> {
         Ok(())
     }
 
-    pub fn get_service(&'a mut self, service_name: &'a str, interface_name: &'a str) -> Result<Service<'a>, Error> {
+    /// The service manager itself, the context object at handle 0, as a `Service`, for calling
+    /// transactions which have no method here. It shares this connection, and, as the context
+    /// object is never freed, takes no reference on it.
+    pub fn context_object(&self) -> Service<'static> {
+        Service::new(
+            self.binder.clone(),
+            SERVICE_MANAGER_INTERFACE_TOKEN,
+            SERVICE_MANAGER_INTERFACE_TOKEN,
+            SERVICE_MANAGER_HANDLE,
+        )
+    }
+
+    pub fn get_service<'s>(&mut self, service_name: &'s str, interface_name: &'s str) -> Result<Service<'s>, Error> {
         let mut parcel = Parcel::empty();
         parcel.write_interface_token(SERVICE_MANAGER_INTERFACE_TOKEN)?;
         parcel.write_str16(service_name)?;
-        let (_transaction, mut parcel) = self.binder.transact(
+        let mut parcel = transact(
+            &self.binder,
             SERVICE_MANAGER_HANDLE,
             ServiceManagerFunctions::GetService as u32,
             TransactionFlags::empty(),
@@ -149,37 +459,569 @@ impl<'a> ServiceManager<'a> {
         parcel.read_u32()?;
         let flat_object = BinderFlatObject::deserialize(&mut parcel)?;
 
-        self.binder.add_ref(flat_object.handle as i32)?;
-        self.binder.acquire(flat_object.handle as i32)?;
+        let mut binder = lock(&self.binder);
+        binder.add_ref(flat_object.handle as i32)?;
+        binder.acquire(flat_object.handle as i32)?;
+
+        Ok(Service::new(self.binder.clone(), service_name, interface_name, flat_object.handle as i32))
+    }
+
+    /// Read a `@nullable` binder, such as one returned by a call, from `parcel` and wrap it as a
+    /// `Service` implementing `interface_name`. A null binder is `None`. The service holds a strong
+    /// reference on the handle, like those returned by `get_service`.
+    pub fn read_strong_binder<'s>(&mut self, parcel: &mut Parcel, interface_name: &'s str) -> Result<Option<Service<'s>>, Error> {
+        let flat_object = match parcel.read_nullable_strong_binder()? {
+            Some(flat_object) => flat_object,
+            None => return Ok(None),
+        };
+        // A binder living in this process can not be called through the driver
+        if flat_object.binder_type != BinderType::Handle {
+            return Err(Error::UnexpectedObjectType {
+                expected: BinderType::Handle,
+                found: flat_object.binder_type,
+            });
+        }
+
+        let handle = flat_object.handle as i32;
+        let mut binder = lock(&self.binder);
+        binder.add_ref(handle)?;
+        binder.acquire(handle)?;
 
-        Ok(Service::new(self, service_name, interface_name, flat_object.handle as i32))
+        Ok(Some(Service::new(self.binder.clone(), interface_name, interface_name, handle)))
     }
 
-    pub fn register_service<BS: BinderService> (
-        &'a mut self,
+    /// Like `get_service`, but also verifies that the remote object actually implements
+    /// `interface_name`, returning `Error::InterfaceMismatch` if it does not.
+    pub fn get_service_checked<'s>(&mut self, service_name: &'s str, interface_name: &'s str) -> Result<Service<'s>, Error> {
+        self.get_service(service_name, interface_name)?
+            .cast_checked(interface_name)
+    }
+
+    /// Publish `service_delegate` under `name`. `dump_priority` must include at least one of the
+    /// priorities in `DumpPriority::ALL`, or this fails with `Error::InvalidDumpPriority`.
+    pub fn register_service<'a, BS: BinderService> (
+        &mut self,
         service_delegate: &'a BS,
         name: &'a str,
         interface_name: &'a str,
         allow_isolated: bool,
-        dump_priority: u32,
+        dump_priority: DumpPriority,
     ) -> Result<ServiceListener<'a, BS>, Error> {
-
-        self.binder.enter_looper()?;
+        // Without a priority, the service would never be dumped
+        if !dump_priority.intersects(DumpPriority::ALL) {
+            return Err(Error::InvalidDumpPriority(dump_priority.bits()));
+        }
 
         let mut parcel = Parcel::empty();
         parcel.write_interface_token(SERVICE_MANAGER_INTERFACE_TOKEN)?;
         parcel.write_str16(name)?;
-        parcel.write_binder(self as *const _ as *const c_void)?;
+        // The delegate's address identifies the published object, and comes back as the cookie of
+        // the transactions sent to it.
+        let object = service_delegate as *const BS as *const c_void;
+        parcel.write_binder_with_cookie(object, object as usize)?;
         parcel.write_bool(allow_isolated)?;
-        parcel.write_u32(dump_priority)?;
+        parcel.write_u32(dump_priority.bits())?;
 
-        let (_transaction, _parcel) = self.binder.transact(
+        transact(
+            &self.binder,
             SERVICE_MANAGER_HANDLE,
             ServiceManagerFunctions::AddService as u32,
             TransactionFlags::empty(),
             &mut parcel,
         )?;
 
-        Ok(ServiceListener::new(service_delegate, self, name, interface_name))
+        Ok(ServiceListener::new(service_delegate, self.binder.clone(), name, interface_name)
+            .with_cookie(object as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{binder::BinderDriverCommandProtocol, testing::MockDriver};
+    use std::{
+        cell::{Cell, RefCell},
+        thread,
+        time::Duration,
+    };
+
+    struct Delegate;
+
+    impl BinderService for Delegate {
+        fn process_request(&self, _code: u32, _data: &mut Parcel) -> Parcel {
+            Parcel::empty()
+        }
+    }
+
+    /// A service manager using `driver`, with the handle `get_service` is going to return
+    fn mock_service_manager() -> (MockDriver, ServiceManager) {
+        let (driver, binder) = MockDriver::new();
+        // The ping
+        driver.queue_reply(&Parcel::empty());
+        let service_manager = ServiceManager::with_binder(binder).unwrap();
+
+        let mut reply = Parcel::empty();
+        reply.write_u32(0).unwrap();
+        BinderFlatObject::new(BinderType::Handle, 5, 0, 0).serialize(&mut reply).unwrap();
+        driver.queue_reply(&reply);
+        (driver, service_manager)
+    }
+
+    fn call_reply(value: i32) -> Parcel {
+        let mut reply = Parcel::empty();
+        reply.write_u32(0).unwrap();
+        reply.write_i32(value).unwrap();
+        reply
+    }
+
+    fn interface_reply(interface_name: &str) -> Parcel {
+        let mut reply = Parcel::empty();
+        reply.write_str16(interface_name).unwrap();
+        reply
+    }
+
+    #[test]
+    fn listener_replies_with_the_interface_descriptor() {
+        let (driver, binder) = MockDriver::new();
+        let mut listener = ServiceListener::new(&Delegate, Arc::new(Mutex::new(binder)), "test", "com.example.ITest");
+        driver.queue_transaction(Transaction::Interface as u32, 0, TransactionFlags::empty(), &Parcel::empty());
+
+        assert!(listener.run_once().unwrap());
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].data.read_str16().unwrap(), "com.example.ITest");
+        assert!(!sent[0].data.has_unread_data());
+    }
+
+    #[test]
+    fn get_service_checked_verifies_the_interface() {
+        let (driver, mut service_manager) = mock_service_manager();
+        driver.queue_reply(&interface_reply("com.example.ITest"));
+        let service = service_manager.get_service_checked("test", "com.example.ITest").unwrap();
+        assert_eq!(service.handle(), 5);
+
+        let (driver, mut service_manager) = mock_service_manager();
+        driver.queue_reply(&interface_reply("com.example.IOther"));
+        match service_manager.get_service_checked("test", "com.example.ITest") {
+            Err(Error::InterfaceMismatch { expected, actual }) => {
+                assert_eq!(expected, "com.example.ITest");
+                assert_eq!(actual, "com.example.IOther");
+            }
+            result => panic!("unexpected result: {:?}", result.map(|service| service.handle())),
+        }
+    }
+
+    #[test]
+    fn two_services_from_one_manager() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut first = service_manager.get_service("first", "com.example.ITest").unwrap();
+        let mut reply = Parcel::empty();
+        reply.write_u32(0).unwrap();
+        BinderFlatObject::new(BinderType::Handle, 6, 0, 0).serialize(&mut reply).unwrap();
+        driver.queue_reply(&reply);
+        let mut second = service_manager.get_service("second", "com.example.ITest").unwrap();
+
+        driver.queue_reply(&call_reply(1));
+        driver.queue_reply(&call_reply(2));
+        assert_eq!(first.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap(), 1);
+        assert_eq!(second.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap(), 2);
+        assert_eq!((first.handle(), second.handle()), (5, 6));
+    }
+
+    /// Answers every call with the result of calling another service
+    struct Forwarder {
+        service: RefCell<Service<'static>>,
+    }
+
+    impl BinderService for Forwarder {
+        fn process_request(&self, code: u32, data: &mut Parcel) -> Parcel {
+            let mut reply = self.service.borrow_mut().call(code, data).unwrap();
+            let mut forwarded = Parcel::empty();
+            forwarded.write_u32(0).unwrap();
+            forwarded.write_i32(reply.read_i32().unwrap()).unwrap();
+            forwarded
+        }
+    }
+
+    #[test]
+    fn delegate_calls_another_service() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let forwarder = Forwarder {
+            service: RefCell::new(service_manager.get_service("other", "com.example.IOther").unwrap()),
+        };
+        let mut listener = ServiceListener::new(&forwarder, service_manager.binder.clone(), "test", "com.example.ITest");
+
+        let mut request = Parcel::empty();
+        request.write_interface_token("com.example.ITest").unwrap();
+        driver.queue_transaction(Transaction::FirstCall as u32, 0, TransactionFlags::empty(), &request);
+        driver.queue_reply(&call_reply(7));
+        driver.take_sent();
+
+        assert!(listener.run_once().unwrap());
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].code, Transaction::FirstCall as u32);
+        assert_eq!(sent[1].data.read_u32().unwrap(), 0);
+        assert_eq!(sent[1].data.read_i32().unwrap(), 7);
+    }
+
+    #[test]
+    fn service_can_be_called_from_another_thread() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        driver.queue_reply(&call_reply(3));
+        let result = thread::spawn(move || service.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap());
+        assert_eq!(result.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn listener_waits_without_holding_the_connection() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        let mut listener = ServiceListener::new(&Delegate, service_manager.binder.clone(), "test", "com.example.ITest");
+
+        thread::scope(|scope| {
+            let listening = scope.spawn(|| listener.process_incoming().unwrap());
+            // Let the listener start waiting for a transaction before calling out
+            thread::sleep(Duration::from_millis(50));
+            driver.queue_reply(&call_reply(3));
+            assert_eq!(service.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap(), 3);
+
+            driver.queue_transaction(Transaction::Interface as u32, 0, TransactionFlags::empty(), &Parcel::empty());
+            assert!(listening.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn listener_refuses_calls_to_another_interface() {
+        let (driver, binder) = MockDriver::new();
+        let mut listener = ServiceListener::new(&Delegate, Arc::new(Mutex::new(binder)), "test", "com.example.ITest");
+        let mut request = Parcel::empty();
+        request.write_interface_token("com.example.IOther").unwrap();
+        driver.queue_transaction(Transaction::FirstCall as u32, 0, TransactionFlags::empty(), &request);
+        assert!(listener.run_once().unwrap());
+        // Not even a token
+        driver.queue_transaction(Transaction::FirstCall as u32, 0, TransactionFlags::empty(), &Parcel::empty());
+        assert!(listener.run_once().unwrap());
+
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        for mut sent in sent {
+            assert!(sent.flags.contains(TransactionFlags::StatusCode));
+            assert_eq!(sent.data.read_i32().unwrap(), PERMISSION_DENIED);
+        }
+    }
+
+    #[test]
+    fn run_once_handles_a_single_transaction() {
+        let (driver, binder) = MockDriver::new();
+        let mut listener = ServiceListener::new(&Delegate, Arc::new(Mutex::new(binder)), "test", "com.example.ITest");
+        assert!(!listener.run_once().unwrap());
+
+        driver.queue_transaction(Transaction::Ping as u32, 0, TransactionFlags::empty(), &Parcel::empty());
+        assert!(listener.run_once().unwrap());
+        assert_eq!(driver.take_transactions().len(), 1);
+        assert!(!listener.run_once().unwrap());
+
+        // A stopped listener returns without waiting for transactions
+        listener.stop();
+        listener.run().unwrap();
+    }
+
+    #[test]
+    fn unknown_reserved_code_is_refused() {
+        let (driver, binder) = MockDriver::new();
+        let mut listener = ServiceListener::new(&Delegate, Arc::new(Mutex::new(binder)), "test", "com.example.ITest");
+        let code = u32::from_be_bytes(*b"_XYZ");
+        driver.queue_transaction(code, 0, TransactionFlags::empty(), &Parcel::empty());
+
+        assert!(listener.run_once().unwrap());
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].flags.contains(TransactionFlags::StatusCode));
+        assert_eq!(sent[0].data.read_i32().unwrap(), UNKNOWN_TRANSACTION);
+    }
+
+    #[test]
+    fn listener_only_handles_its_cookie() {
+        let (driver, binder) = MockDriver::new();
+        let mut listener =
+            ServiceListener::new(&Delegate, Arc::new(Mutex::new(binder)), "test", "com.example.ITest").with_cookie(0x42);
+        driver.queue_transaction(Transaction::Interface as u32, 0x42, TransactionFlags::empty(), &Parcel::empty());
+        assert!(listener.run_once().unwrap());
+        driver.queue_transaction(Transaction::Interface as u32, 0x43, TransactionFlags::empty(), &Parcel::empty());
+        assert!(listener.run_once().unwrap());
+
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].data.read_str16().unwrap(), "com.example.ITest");
+        assert!(sent[1].flags.contains(TransactionFlags::StatusCode));
+        assert_eq!(sent[1].data.read_i32().unwrap(), UNKNOWN_TRANSACTION);
+    }
+
+    #[test]
+    fn cast_service_uses_the_new_token() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.IBase").unwrap();
+        driver.take_sent();
+        driver.queue_reply(&call_reply(1));
+        driver.queue_reply(&call_reply(2));
+        service.call(1, &mut Parcel::empty()).unwrap();
+        let mut service = service.cast("com.example.IDerived");
+        service.call(1, &mut Parcel::empty()).unwrap();
+        assert_eq!(service.handle(), 5);
+
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].data.read_interface_token().unwrap(), "com.example.IBase");
+        assert_eq!(sent[1].data.read_interface_token().unwrap(), "com.example.IDerived");
+
+        driver.queue_reply(&interface_reply("com.example.IDerived"));
+        assert!(service.cast_checked("com.example.IDerived").is_ok());
+    }
+
+    #[test]
+    fn published_cookie_identifies_the_delegate() {
+        let (driver, binder) = MockDriver::new();
+        driver.queue_reply(&Parcel::empty());
+        let mut service_manager = ServiceManager::with_binder(binder).unwrap();
+        driver.take_sent();
+        driver.queue_reply(&Parcel::empty());
+        let delegate = Delegate;
+        let listener = service_manager
+            .register_service(&delegate, "test", "com.example.ITest", false, DumpPriority::DEFAULT)
+            .unwrap();
+
+        let mut sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].code, ServiceManagerFunctions::AddService as u32);
+        let parcel = &mut sent[0].data;
+        parcel.read_interface_token().unwrap();
+        assert_eq!(parcel.read_str16().unwrap(), "test");
+        let object = BinderFlatObject::deserialize(parcel).unwrap();
+        let address = &delegate as *const Delegate as usize;
+        assert_eq!((object.handle(), object.cookie()), (address, address));
+        assert_eq!(listener.cookie(), address as u64);
+    }
+
+    #[test]
+    fn cached_token_gives_the_same_request() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        driver.take_sent();
+        let mut data = Parcel::empty();
+        data.write_i32(7).unwrap();
+        data.write_file_descriptor(5, false).unwrap();
+
+        // What the request was before the token was cached: a fresh token, then the data
+        let mut expected = Parcel::empty();
+        expected.write_interface_token("com.example.ITest").unwrap();
+        expected.append_parcel(&mut data.clone()).unwrap();
+
+        driver.queue_reply(&call_reply(1));
+        driver.queue_reply(&call_reply(2));
+        service.call(1, &mut data).unwrap();
+        service.call(1, &mut data).unwrap();
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].data, expected);
+        assert_eq!(sent[1].data, expected);
+    }
+
+    #[test]
+    fn clear_buf_is_sent_with_the_call() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        driver.take_sent();
+        driver.queue_reply(&call_reply(1));
+        driver.queue_reply(&call_reply(2));
+        service.call_with_flags(1, TransactionFlags::ClearBuf, &mut Parcel::empty()).unwrap();
+        service.call(1, &mut Parcel::empty()).unwrap();
+
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[0].flags.contains(TransactionFlags::ClearBuf | TransactionFlags::AcceptFds));
+        assert!(!sent[1].flags.contains(TransactionFlags::ClearBuf));
+    }
+
+    /// Counts the sysprops notifications it gets
+    #[derive(Default)]
+    struct SyspropsCounter {
+        changes: Cell<u32>,
+    }
+
+    impl BinderService for SyspropsCounter {
+        fn process_request(&self, _code: u32, _data: &mut Parcel) -> Parcel {
+            Parcel::empty()
+        }
+
+        fn on_sysprops_changed(&self) {
+            self.changes.set(self.changes.get() + 1);
+        }
+    }
+
+    #[test]
+    fn sysprops_transaction_is_acknowledged() {
+        let (driver, binder) = MockDriver::new();
+        let counter = SyspropsCounter::default();
+        let mut listener = ServiceListener::new(&counter, Arc::new(Mutex::new(binder)), "test", "com.example.ITest");
+        driver.queue_transaction(Transaction::Sysprops as u32, 0, TransactionFlags::empty(), &Parcel::empty());
+
+        assert!(listener.run_once().unwrap());
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].command, BinderDriverCommandProtocol::Reply as u32);
+        assert!(!sent[0].flags.contains(TransactionFlags::StatusCode));
+        assert!(sent[0].data.is_empty());
+        assert_eq!(counter.changes.get(), 1);
+    }
+
+    #[test]
+    fn handle_is_the_resolved_one() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut reply = Parcel::empty();
+        reply.write_u32(0).unwrap();
+        BinderFlatObject::new(BinderType::Handle, 9, 0, 0).serialize(&mut reply).unwrap();
+        driver.queue_reply(&reply);
+        let service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        let other = service_manager.get_service("other", "com.example.ITest").unwrap();
+        assert_eq!((service.handle(), other.handle()), (5, 9));
+
+        // A raw transaction on the handle, outside of `call`
+        driver.queue_reply(&call_reply(4));
+        let handle = service.handle();
+        let (_, mut parcel) = service
+            .binder()
+            .transact(handle, 1, TransactionFlags::empty(), &mut Parcel::empty())
+            .unwrap();
+        assert_eq!(parcel.read_u32().unwrap(), 0);
+        assert_eq!(parcel.read_i32().unwrap(), 4);
+    }
+
+    #[test]
+    fn dump_priority_is_sent_as_its_bits() {
+        assert_eq!(DumpPriority::ALL.bits(), 0xf);
+        assert_eq!((DumpPriority::HIGH | DumpPriority::PROTO).bits(), 0x12);
+
+        let (driver, binder) = MockDriver::new();
+        driver.queue_reply(&Parcel::empty());
+        let mut service_manager = ServiceManager::with_binder(binder).unwrap();
+        driver.take_sent();
+        driver.queue_reply(&Parcel::empty());
+        service_manager
+            .register_service(&Delegate, "test", "com.example.ITest", true, DumpPriority::HIGH | DumpPriority::PROTO)
+            .unwrap();
+        let mut sent = driver.take_transactions();
+        let parcel = &mut sent[0].data;
+        parcel.read_interface_token().unwrap();
+        parcel.read_str16().unwrap();
+        parcel.read_strong_binder().unwrap();
+        assert_eq!(parcel.read_u32().unwrap(), 1);
+        assert_eq!(parcel.read_u32().unwrap(), 0x12);
+
+        // Dumping in protobuf format alone gives no priority to be dumped with
+        assert!(matches!(
+            service_manager.register_service(&Delegate, "test", "com.example.ITest", false, DumpPriority::PROTO),
+            Err(Error::InvalidDumpPriority(0x10))
+        ));
+        assert!(driver.take_transactions().is_empty());
+    }
+
+    #[test]
+    fn nullable_strong_binder_as_a_service() {
+        let (driver, binder) = MockDriver::new();
+        driver.queue_reply(&Parcel::empty());
+        let mut service_manager = ServiceManager::with_binder(binder).unwrap();
+        driver.take_sent();
+
+        // A null binder, as Android writes it: a local binder with a null pointer and no offset
+        let mut parcel = Parcel::empty();
+        parcel.write_u32(BinderType::Binder as u32).unwrap();
+        parcel.write_u32(0).unwrap();
+        parcel.write_usize(0).unwrap();
+        parcel.write_usize(0).unwrap();
+        parcel.write_u32(0xc).unwrap();
+        BinderFlatObject::new(BinderType::Handle, 7, 0, 0).serialize(&mut parcel).unwrap();
+        parcel.set_position(0);
+
+        assert!(service_manager.read_strong_binder(&mut parcel, "com.example.ITest").unwrap().is_none());
+        let mut service = service_manager.read_strong_binder(&mut parcel, "com.example.ITest").unwrap().unwrap();
+        assert_eq!(service.handle(), 7);
+
+        // The references taken on the handle go out with the next call
+        driver.queue_reply(&call_reply(1));
+        service.call(1, &mut Parcel::empty()).unwrap();
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(
+            commands,
+            [
+                BinderDriverCommandProtocol::IncRefs as u32,
+                BinderDriverCommandProtocol::Acquire as u32,
+                BinderDriverCommandProtocol::Transaction as u32
+            ]
+        );
+    }
+
+    #[test]
+    fn services_outlive_the_manager() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut foo = service_manager.get_service("foo", "com.example.IFoo").unwrap();
+        let mut reply = Parcel::empty();
+        reply.write_u32(0).unwrap();
+        BinderFlatObject::new(BinderType::Handle, 6, 0, 0).serialize(&mut reply).unwrap();
+        driver.queue_reply(&reply);
+        let mut bar = service_manager.get_service("bar", "com.example.IBar").unwrap();
+        // Both hold the shared connection, so neither borrows the manager
+        drop(service_manager);
+
+        driver.queue_reply(&call_reply(1));
+        driver.queue_reply(&call_reply(2));
+        assert_eq!(bar.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap(), 1);
+        assert_eq!(foo.call(1, &mut Parcel::empty()).unwrap().read_i32().unwrap(), 2);
+    }
+
+    #[test]
+    fn listener_accepts_calls_with_a_work_source() {
+        let (driver, mut service_manager) = mock_service_manager();
+        let mut service = service_manager.get_service("test", "com.example.ITest").unwrap();
+        driver.queue_reply(&call_reply(3));
+        {
+            let _guard = Binder::set_work_source_uid(1000);
+            service.call(1, &mut Parcel::empty()).unwrap();
+        }
+        let mut request = driver.take_transactions().pop().unwrap().data;
+        request.set_position(4);
+        assert_eq!(request.read_i32().unwrap(), 1000);
+
+        let mut listener = ServiceListener::new(&Delegate, service_manager.binder.clone(), "test", "com.example.ITest");
+        driver.queue_transaction(Transaction::FirstCall as u32, 0, TransactionFlags::empty(), &request);
+        assert!(listener.run_once().unwrap());
+        let sent = driver.take_transactions();
+        assert_eq!(sent.len(), 1);
+        assert!(!sent[0].flags.contains(TransactionFlags::StatusCode));
+    }
+
+    #[test]
+    fn listener_enters_the_looper_on_its_own_thread() {
+        let (driver, binder) = MockDriver::new();
+        driver.queue_reply(&Parcel::empty());
+        let mut service_manager = ServiceManager::with_binder(binder).unwrap();
+        driver.queue_reply(&Parcel::empty());
+        let mut listener = service_manager
+            .register_service(&Delegate, "test", "com.example.ITest", false, DumpPriority::DEFAULT)
+            .unwrap();
+        assert!(!lock(&service_manager.binder).is_looper_entered());
+        driver.take_sent();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                assert!(!listener.run_once().unwrap());
+                assert!(!listener.run_once().unwrap());
+                assert!(lock(&service_manager.binder).is_looper_entered());
+            });
+        });
+        assert!(!lock(&service_manager.binder).is_looper_entered());
+        let commands: Vec<_> = driver.take_sent().iter().map(|sent| sent.command).collect();
+        assert_eq!(commands, [BinderDriverCommandProtocol::EnterLooper as u32]);
     }
 }
@@ -0,0 +1,390 @@
+//! Helpers shared by the unit tests
+
+pub(crate) use driver::MockDriver;
+
+#[cfg(feature = "parcel-trace")]
+pub(crate) use trace::capture_trace;
+
+#[cfg(feature = "parcel-trace")]
+mod trace {
+    use std::{
+        sync::Mutex,
+        thread::{self, ThreadId},
+    };
+
+    /// Every trace line logged, with the thread which logged it, since tests run in parallel
+    static LINES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() == log::Level::Trace
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                LINES.lock().unwrap().push((thread::current().id(), record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger;
+
+    /// Run `f`, returning the trace lines it logged
+    pub(crate) fn capture_trace(f: impl FnOnce()) -> Vec<String> {
+        // Only the first test to get here installs the logger, the others share it
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let thread = thread::current().id();
+        LINES.lock().unwrap().retain(|(id, _)| *id != thread);
+        f();
+        LINES
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| *id == thread)
+            .map(|(_, line)| line.clone())
+            .collect()
+    }
+}
+
+mod driver {
+    use crate::{
+        Binder, BinderDriverCommandProtocol, BinderDriverReturnProtocol, BinderTransactionData,
+        BinderWriteRead, Parcel, Parcelable, TransactionFlags, Transport, MAX_TRANSACTION_SIZE,
+    };
+    use nix::{errno::Errno, fcntl::OFlag, sys::stat::Mode};
+    use std::{
+        collections::{HashMap, HashSet, VecDeque},
+        convert::TryInto,
+        ffi::c_void,
+        slice,
+        sync::{Arc, Condvar, Mutex, MutexGuard},
+        thread::{self, ThreadId},
+        time::{Duration, Instant},
+    };
+
+    /// How long a read waits for work before the test is taken to be stuck
+    const STUCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Return commands for one thread, delivered whole in a single read
+    struct Work {
+        data: Vec<u8>,
+        /// Whether this answers the thread's pending two-way transaction
+        is_reply: bool,
+    }
+
+    /// A command written to the driver, with the transaction for `BC_TRANSACTION` and `BC_REPLY`
+    #[derive(Debug)]
+    pub(crate) struct Sent {
+        pub(crate) command: u32,
+        pub(crate) cookie: u64,
+        pub(crate) code: u32,
+        pub(crate) flags: TransactionFlags,
+        pub(crate) data: Parcel,
+    }
+
+    struct State {
+        /// Stands in for the mapping of the driver's buffer, which incoming data is copied into
+        buffer: Vec<u8>,
+        next_free: usize,
+        /// Answers to the next two-way transactions, in order
+        replies: VecDeque<Vec<u8>>,
+        /// Incoming transactions, for any thread not waiting for a reply
+        process_work: VecDeque<Vec<u8>>,
+        thread_work: HashMap<ThreadId, VecDeque<Work>>,
+        awaiting_reply: HashSet<ThreadId>,
+        /// Errors to fail the next write/reads with
+        failures: VecDeque<Errno>,
+        /// How long each read takes before returning its work
+        read_delay: Duration,
+        sent: Vec<Sent>,
+    }
+
+    impl State {
+        /// Copy `data` into the buffer, returning its address there
+        fn copy_in(&mut self, data: &[u8]) -> *mut u8 {
+            if self.next_free + data.len() > self.buffer.len() {
+                self.next_free = 0;
+            }
+            let start = self.next_free;
+            self.buffer[start..start + data.len()].copy_from_slice(data);
+            // Keep the offsets which may follow aligned
+            self.next_free = (start + data.len() + 7) & !7;
+            self.buffer[start..].as_mut_ptr()
+        }
+
+        /// Encode `parcel` as a `BR_REPLY` or `BR_TRANSACTION`
+        fn transaction(&mut self, command: BinderDriverReturnProtocol, code: u32, cookie: u64, flags: TransactionFlags, parcel: &Parcel) -> Vec<u8> {
+            let offsets: Vec<u8> = parcel.offsets().iter().flat_map(|offset| offset.to_ne_bytes()).collect();
+            let transaction = BinderTransactionData {
+                target: 0,
+                cookie,
+                code,
+                flags: flags.bits(),
+                sender_pid: 0,
+                sender_euid: 0,
+                data_size: parcel.len() as u64,
+                offset_size: offsets.len() as u64,
+                data: self.copy_in(parcel.to_slice()),
+                offsets: self.copy_in(&offsets) as *mut usize,
+            };
+            let mut work = Parcel::empty();
+            work.write_u32(command as u32).unwrap();
+            transaction.serialize(&mut work).unwrap();
+            work.into_vec()
+        }
+
+        /// Act on the commands written by `thread`, as the driver would
+        fn write(&mut self, thread: ThreadId, commands: &[u8]) {
+            let mut commands = Parcel::from_slice(commands);
+            while commands.has_unread_data() {
+                let command = commands.read_u32().unwrap();
+                if command != BinderDriverCommandProtocol::Transaction as u32
+                    && command != BinderDriverCommandProtocol::Reply as u32
+                {
+                    // Skip the arguments, whose size is encoded in the command
+                    commands.skip(((command >> 16) & 0x3fff) as usize).unwrap();
+                    self.sent.push(Sent {
+                        command,
+                        cookie: 0,
+                        code: 0,
+                        flags: TransactionFlags::empty(),
+                        data: Parcel::empty(),
+                    });
+                    continue;
+                }
+
+                let transaction = commands.read_transaction_data().unwrap();
+                let copy = |pointer: *const u8, size: u64| match size {
+                    0 => vec![],
+                    size => unsafe { slice::from_raw_parts(pointer, size as usize) }.to_vec(),
+                };
+                let data = copy(transaction.data, transaction.data_size);
+                let offsets = copy(transaction.offsets as *const u8, transaction.offset_size)
+                    .chunks_exact(std::mem::size_of::<usize>())
+                    .map(|offset| usize::from_ne_bytes(offset.try_into().unwrap()))
+                    .collect();
+                self.sent.push(Sent {
+                    command,
+                    cookie: transaction.cookie(),
+                    code: transaction.code(),
+                    flags: transaction.flags(),
+                    data: Parcel::from_parts(data, offsets),
+                });
+
+                let complete = (BinderDriverReturnProtocol::TransactionComplete as u32).to_ne_bytes().to_vec();
+                self.queue(thread, complete, false);
+                if command == BinderDriverCommandProtocol::Transaction as u32
+                    && !transaction.flags().contains(TransactionFlags::OneWay)
+                {
+                    self.awaiting_reply.insert(thread);
+                    if let Some(reply) = self.replies.pop_front() {
+                        self.queue(thread, reply, true);
+                    }
+                }
+            }
+        }
+
+        fn queue(&mut self, thread: ThreadId, data: Vec<u8>, is_reply: bool) {
+            self.thread_work.entry(thread).or_default().push_back(Work { data, is_reply });
+        }
+
+        fn has_work(&self, thread: ThreadId) -> bool {
+            matches!(self.thread_work.get(&thread), Some(work) if !work.is_empty())
+                || (!self.awaiting_reply.contains(&thread) && !self.process_work.is_empty())
+        }
+
+        /// Fill `read` with as much of `thread`'s work as fits, returning how much was read
+        fn read(&mut self, thread: ThreadId, read: &mut [u8]) -> usize {
+            let mut consumed = 0;
+            while let Some(work) = self.thread_work.get_mut(&thread).and_then(|work| work.pop_front()) {
+                if consumed + work.data.len() > read.len() || (consumed != 0 && work.data.is_empty()) {
+                    self.thread_work.get_mut(&thread).unwrap().push_front(work);
+                    return consumed;
+                }
+                read[consumed..consumed + work.data.len()].copy_from_slice(&work.data);
+                consumed += work.data.len();
+                if work.is_reply {
+                    self.awaiting_reply.remove(&thread);
+                }
+                // An empty read stays empty
+                if work.data.is_empty() {
+                    return 0;
+                }
+            }
+            if !self.awaiting_reply.contains(&thread) {
+                while let Some(work) = self.process_work.pop_front() {
+                    if consumed + work.len() > read.len() {
+                        self.process_work.push_front(work);
+                        break;
+                    }
+                    read[consumed..consumed + work.len()].copy_from_slice(&work);
+                    consumed += work.len();
+                }
+            }
+            consumed
+        }
+    }
+
+    /// Stands in for the binder driver behind a `Binder`, so that transactions can be tested
+    /// without a device. As with the driver, each thread waiting on its own transaction gets the
+    /// reply, while incoming transactions go to any other thread which reads.
+    #[derive(Clone)]
+    pub(crate) struct MockDriver {
+        state: Arc<(Mutex<State>, Condvar)>,
+    }
+
+    impl MockDriver {
+        /// Create the driver, with a connection to it
+        pub(crate) fn new() -> (Self, Binder) {
+            let driver = Self {
+                state: Arc::new((
+                    Mutex::new(State {
+                        buffer: vec![0; MAX_TRANSACTION_SIZE],
+                        next_free: 0,
+                        replies: VecDeque::new(),
+                        process_work: VecDeque::new(),
+                        thread_work: HashMap::new(),
+                        awaiting_reply: HashSet::new(),
+                        failures: VecDeque::new(),
+                        read_delay: Duration::ZERO,
+                        sent: vec![],
+                    }),
+                    Condvar::new(),
+                )),
+            };
+            let mem = driver.lock().buffer.as_ptr() as *const c_void;
+            // Only closed by the `Binder`, and never used otherwise
+            let fd = nix::fcntl::open("/dev/null", OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()).unwrap();
+            let binder = Binder::with_transport(fd, mem, Arc::new(driver.clone()));
+            (driver, binder)
+        }
+
+        fn lock(&self) -> MutexGuard<'_, State> {
+            self.state.0.lock().unwrap()
+        }
+
+        fn notify(&self) {
+            self.state.1.notify_all();
+        }
+
+        /// Answer the next two-way transaction with `reply`
+        pub(crate) fn queue_reply(&self, reply: &Parcel) {
+            let mut state = self.lock();
+            let reply = state.transaction(BinderDriverReturnProtocol::Reply, 0, 0, TransactionFlags::empty(), reply);
+            state.replies.push_back(reply);
+        }
+
+        /// Answer the next two-way transaction with `command`, such as `BR_DEAD_REPLY`, instead of
+        /// a reply
+        pub(crate) fn queue_reply_command(&self, command: BinderDriverReturnProtocol) {
+            self.lock().replies.push_back((command as u32).to_ne_bytes().to_vec());
+        }
+
+        /// Send an incoming transaction to the process
+        pub(crate) fn queue_transaction(&self, code: u32, cookie: u64, flags: TransactionFlags, data: &Parcel) {
+            let mut state = self.lock();
+            let transaction = state.transaction(BinderDriverReturnProtocol::Transaction, code, cookie, flags, data);
+            state.process_work.push_back(transaction);
+            drop(state);
+            self.notify();
+        }
+
+        /// Give the calling thread's next read `data`, which may be empty, on its own
+        pub(crate) fn queue_read(&self, data: Vec<u8>) {
+            self.lock().queue(thread::current().id(), data, false);
+            self.notify();
+        }
+
+        /// Fail the next write/read with `errno`
+        pub(crate) fn fail_next(&self, errno: Errno) {
+            self.lock().failures.push_back(errno);
+        }
+
+        /// Make each read take `delay`, as it would when waiting on a slow service
+        #[cfg(feature = "transaction-timing")]
+        pub(crate) fn set_read_delay(&self, delay: Duration) {
+            self.lock().read_delay = delay;
+        }
+
+        /// Take the commands written to the driver so far
+        pub(crate) fn take_sent(&self) -> Vec<Sent> {
+            std::mem::take(&mut self.lock().sent)
+        }
+
+        /// Take the transactions and replies written to the driver so far
+        pub(crate) fn take_transactions(&self) -> Vec<Sent> {
+            self.take_sent()
+                .into_iter()
+                .filter(|sent| {
+                    sent.command == BinderDriverCommandProtocol::Transaction as u32
+                        || sent.command == BinderDriverCommandProtocol::Reply as u32
+                })
+                .collect()
+        }
+
+        /// Wait until the calling thread has work, or `timeout` has passed
+        fn wait(&self, timeout: Duration) -> MutexGuard<'_, State> {
+            let thread = thread::current().id();
+            let deadline = Instant::now() + timeout;
+            let mut state = self.lock();
+            while !state.has_work(thread) {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.state.1.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            state
+        }
+    }
+
+    impl Transport for MockDriver {
+        fn write_read(&self, write_read: &mut BinderWriteRead) -> nix::Result<()> {
+            let thread = thread::current().id();
+            if write_read.write_consumed < write_read.write_size {
+                let commands = unsafe {
+                    slice::from_raw_parts(write_read.write_buffer as *const u8, write_read.write_size)
+                };
+                self.lock().write(thread, &commands[write_read.write_consumed..]);
+                write_read.write_consumed = write_read.write_size;
+                self.notify();
+            }
+            if let Some(errno) = self.lock().failures.pop_front() {
+                return Err(nix::Error::Sys(errno));
+            }
+            if write_read.read_size == 0 {
+                return Ok(());
+            }
+
+            let read_delay = self.lock().read_delay;
+            thread::sleep(read_delay);
+            let mut state = self.wait(STUCK_TIMEOUT);
+            assert!(state.has_work(thread), "the read would block forever");
+            let read = unsafe {
+                slice::from_raw_parts_mut(write_read.read_buffer as *mut u8, write_read.read_size)
+            };
+            write_read.read_consumed += state.read(thread, &mut read[write_read.read_consumed..]);
+            Ok(())
+        }
+
+        fn poll(&self, timeout: i32) -> nix::Result<bool> {
+            let timeout = if timeout < 0 {
+                STUCK_TIMEOUT
+            } else {
+                Duration::from_millis(timeout as u64)
+            };
+            let has_work = self.wait(timeout).has_work(thread::current().id());
+            Ok(has_work)
+        }
+
+        fn version(&self) -> nix::Result<i32> {
+            Ok(8)
+        }
+    }
+}